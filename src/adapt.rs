@@ -0,0 +1,190 @@
+//! Adaptive compression level: moving the level up while the writer is
+//! slower than compression, and down while compression is slower.
+
+use std::io::{Error as IoError, Write};
+use std::time::{Duration, Instant};
+
+use super::params::DEFAULT_LEVEL;
+
+/// Blocks compressed between changes of level.
+const PERIOD: u64 = 4;
+/// How many times longer one side has to take than the other for the
+/// level to change.
+const IMBALANCE: u32 = 2;
+
+/// Picks the level for the next blocks from how long the last ones took to
+/// compress and to write.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Adapt {
+    level: i32,
+    min: i32,
+    max: i32,
+    /// Blocks, time spent compressing and time spent writing since the
+    /// last change.
+    blocks: u64,
+    compressing: Duration,
+    writing: Duration,
+}
+
+impl Adapt {
+    /// Start at `level`, staying between `min` and `max` (0 standing for
+    /// the default level, as usual).
+    pub(crate) fn new(level: i32, min: i32, max: i32) -> Self {
+        let level_number = |level| if level == 0 { DEFAULT_LEVEL } else { level };
+        let (min, max) = (level_number(min), level_number(max));
+        let max = max.max(min);
+        Self {
+            level: level_number(level).clamp(min, max),
+            min,
+            max,
+            blocks: 0,
+            compressing: Duration::ZERO,
+            writing: Duration::ZERO,
+        }
+    }
+
+    /// The level to start with.
+    pub(crate) fn level(&self) -> i32 {
+        self.level
+    }
+
+    /// Record `blocks` that took `total` to compress and write, of which
+    /// `writing` went to the writer.  Returns the level for the next blocks
+    /// when it changes.
+    pub(crate) fn record(
+        &mut self,
+        blocks: u64,
+        total: Duration,
+        writing: Duration,
+    ) -> Option<i32> {
+        self.blocks += blocks;
+        self.compressing += total.saturating_sub(writing);
+        self.writing += writing;
+        if self.blocks < PERIOD {
+            return None;
+        }
+        let step = if self.writing > self.compressing * IMBALANCE {
+            1
+        } else if self.compressing > self.writing * IMBALANCE {
+            -1
+        } else {
+            0
+        };
+        self.blocks = 0;
+        self.compressing = Duration::ZERO;
+        self.writing = Duration::ZERO;
+
+        // Level 0 is another name for the default, so step over it.
+        let mut level = self.level + step;
+        if level == 0 {
+            level += step;
+        }
+        let level = level.clamp(self.min, self.max);
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(level)
+    }
+}
+
+/// A writer that counts the time spent in the one it wraps.
+pub(crate) struct Timed<W> {
+    inner: W,
+    pub(crate) spent: Duration,
+}
+
+impl<W: Write> Timed<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self { inner, spent: Duration::ZERO }
+    }
+}
+
+impl<W: Write> Write for Timed<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let started = Instant::now();
+        let result = self.inner.write(buf);
+        self.spent += started.elapsed();
+        result
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        let started = Instant::now();
+        let result = self.inner.flush();
+        self.spent += started.elapsed();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Adapt;
+    use crate::tests::{decompress, random};
+    use crate::{CompressionParams, Encoder};
+    use std::io::Write;
+    use std::time::Duration;
+
+    #[test]
+    fn follows_the_slower_side() {
+        let ms = Duration::from_millis;
+        let mut adapt = Adapt::new(3, -1, 5);
+        // Nothing changes until a few blocks are in.
+        assert_eq!(adapt.record(1, ms(10), ms(9)), None);
+        assert_eq!(adapt.record(3, ms(30), ms(27)), Some(4));
+        assert_eq!(adapt.record(4, ms(40), ms(30)), Some(5));
+        assert_eq!(adapt.record(4, ms(40), ms(30)), None);
+        // Even times leave the level alone.
+        assert_eq!(adapt.record(4, ms(40), ms(20)), None);
+        for &level in &[4, 3, 2, 1, -1] {
+            assert_eq!(adapt.record(4, ms(40), ms(1)), Some(level));
+        }
+        assert_eq!(adapt.record(4, ms(40), ms(1)), None);
+        assert_eq!(Adapt::new(0, 1, 2).level(), 2);
+    }
+
+    /// About `len` bytes of made up log lines.
+    fn log_lines(len: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut random = random(9);
+        while data.len() < len {
+            let state = random.next().unwrap();
+            let line = format!("{} entry {} of {};\n", state % 7, state % 4001, state % 89);
+            data.extend_from_slice(line.as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn level_changes_round_trip() {
+        let data = log_lines(3 << 20);
+        // Writing to a `Vec` is quick, so the level goes down as it goes.
+        let params = CompressionParams::level(7).job_size(1 << 18);
+        let long = params.long_distance(true);
+        for &(params, workers) in &[(params, 0), (params, 2), (long, 0)] {
+            let mut frame = Vec::new();
+            let mut encoder = Encoder::new(&mut frame).params(params).adapt(-3, 9).workers(workers);
+            for chunk in data.chunks(100_000) {
+                encoder.write_all(chunk).unwrap();
+            }
+            encoder.finish().unwrap();
+            assert!(decompress(&frame) == data);
+        }
+    }
+
+    #[test]
+    fn starts_within_the_bounds() {
+        let data = log_lines(1 << 20);
+        // The bounds leave out level 1, and pin the level to 12.
+        let compress = |encoder: Encoder<Vec<u8>>| {
+            let mut encoder = encoder.workers(0);
+            encoder.write_all(&data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let pinned = compress(Encoder::new(Vec::new()).level(1).adapt(12, 12));
+        let level_1 = compress(Encoder::new(Vec::new()).level(1));
+        let level_12 = CompressionParams::level(1).at_level(12);
+        assert!(pinned == compress(Encoder::new(Vec::new()).params(level_12)));
+        assert!(pinned.len() < level_1.len() * 19 / 20);
+        assert!(decompress(&pinned) == data);
+    }
+}
@@ -0,0 +1,195 @@
+//! Binary tree match finder, which finds every useful match at a position.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/compress/zstd_opt.c
+
+use super::matcher::{self, HASH_READ_SIZE};
+use super::params::CompressionParams;
+
+/// Most positions to skip inserting after finding a long match.
+const MAX_SKIP: usize = 192;
+
+/// Keeps the positions sharing a hash in a binary tree sorted by the data
+/// that follows them, so searching finds matches of increasing length.
+#[derive(Clone)]
+pub(crate) struct BinaryTree {
+    hash_table: Vec<u32>,
+    /// Smaller and larger child of each position.
+    tree: Vec<u32>,
+    hash_log: u8,
+    tree_mask: usize,
+    attempts: usize,
+    min_match: u8,
+    window: usize,
+    next_to_update: usize,
+}
+
+impl BinaryTree {
+    /// Create a binary tree match finder.
+    pub(crate) fn new(params: &CompressionParams) -> Self {
+        Self {
+            hash_table: vec![0; 1 << params.hash_log],
+            tree: vec![0; 1 << params.chain_log],
+            hash_log: params.hash_log,
+            tree_mask: (1 << (params.chain_log - 1)) - 1,
+            attempts: 1 << params.search_log,
+            min_match: params.min_match.clamp(3, 6),
+            window: params.window(),
+            next_to_update: 1,
+        }
+    }
+
+    /// Forget all positions.
+    pub(crate) fn reset(&mut self) {
+        self.hash_table.iter_mut().for_each(|entry| *entry = 0);
+        self.tree.iter_mut().for_each(|entry| *entry = 0);
+        self.next_to_update = 1;
+    }
+
+    /// Move every position back by `amount`, a multiple of the tree size.
+    pub(crate) fn slide(&mut self, amount: usize) {
+        matcher::slide(&mut self.hash_table, amount);
+        matcher::slide(&mut self.tree, amount);
+        self.next_to_update = self.next_to_update.saturating_sub(amount).max(1);
+    }
+
+    /// Leave the positions before `end` out of the tree.
+    pub(crate) fn skip(&mut self, end: usize) {
+        self.next_to_update = self.next_to_update.max(end);
+    }
+
+    /// Insert the positions before `end`, skipping over the middle of long
+    /// matches, without collecting any.
+    pub(crate) fn fill(&mut self, data: &[u8], end: usize) {
+        self.update(data, end.saturating_sub(HASH_READ_SIZE), end);
+    }
+
+    /// Shortest match worth finding.
+    pub(crate) fn min_match(&self) -> usize {
+        usize::from(self.min_match)
+    }
+
+    /// Largest distance a match may reach back.
+    pub(crate) fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Insert `ip` into the tree, appending (length, offset) for each match
+    /// longer than `best_len` to `matches`.  Returns the longest match.
+    fn insert(
+        &mut self,
+        data: &[u8],
+        ip: usize,
+        end: usize,
+        mut best_len: usize,
+        mut matches: Option<&mut Vec<(usize, usize)>>,
+    ) -> usize {
+        let hash = matcher::hash(data, ip, self.min_match, self.hash_log);
+        let mut candidate = self.hash_table[hash] as usize;
+        self.hash_table[hash] = ip as u32;
+
+        let lowest = ip.saturating_sub(self.window).max(1);
+        let tree_low = ip.saturating_sub(self.tree_mask);
+        // Where to attach the next smaller and larger position.
+        let mut smaller = Some(2 * (ip & self.tree_mask));
+        let mut larger = Some(2 * (ip & self.tree_mask) + 1);
+        let (mut common_smaller, mut common_larger) = (0, 0);
+        let mut longest = 0;
+
+        for _ in 0..self.attempts {
+            if candidate < lowest {
+                break;
+            }
+            let node = 2 * (candidate & self.tree_mask);
+            let mut len = common_smaller.min(common_larger);
+            len += matcher::match_len(data, candidate + len, ip + len, end);
+            longest = longest.max(len);
+            if len > best_len {
+                best_len = len;
+                if let Some(matches) = matches.as_mut() {
+                    matches.push((len, ip - candidate));
+                }
+            }
+            if ip + len == end {
+                // Can't tell which side it belongs on.
+                break;
+            }
+
+            if data[candidate + len] < data[ip + len] {
+                self.tree[smaller.unwrap()] = candidate as u32;
+                common_smaller = len;
+                if candidate <= tree_low {
+                    smaller = None;
+                    break;
+                }
+                smaller = Some(node + 1);
+                candidate = self.tree[node + 1] as usize;
+            } else {
+                self.tree[larger.unwrap()] = candidate as u32;
+                common_larger = len;
+                if candidate <= tree_low {
+                    larger = None;
+                    break;
+                }
+                larger = Some(node);
+                candidate = self.tree[node] as usize;
+            }
+        }
+
+        for slot in smaller.into_iter().chain(larger) {
+            self.tree[slot] = 0;
+        }
+        longest
+    }
+
+    /// Insert every position before `target`, skipping over the middle of
+    /// long matches.
+    fn update(&mut self, data: &[u8], target: usize, end: usize) {
+        let mut pos = self.next_to_update;
+        while pos < target {
+            let longest = self.insert(data, pos, end, 0, None);
+            pos += longest.saturating_sub(HASH_READ_SIZE).clamp(1, MAX_SKIP);
+        }
+        self.next_to_update = self.next_to_update.max(target);
+    }
+
+    /// Append matches at `ip` longer than `best_len` to `matches`, shortest
+    /// first.  `ip` must be before `end - HASH_READ_SIZE`.
+    pub(crate) fn find(
+        &mut self,
+        data: &[u8],
+        ip: usize,
+        end: usize,
+        best_len: usize,
+        matches: &mut Vec<(usize, usize)>,
+    ) {
+        if ip < self.next_to_update {
+            return;
+        }
+        self.update(data, ip, end);
+        self.insert(data, ip, end, best_len.max(self.min_match() - 1), Some(matches));
+        self.next_to_update = ip + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BinaryTree;
+    use crate::params::CompressionParams;
+
+    #[test]
+    fn matches_grow_longer() {
+        let data = b"abcdXabcdeYabcdefZabcdeY--------".to_vec();
+        let mut tree = BinaryTree::new(&CompressionParams::level(19).adjust(1024));
+        let mut matches = Vec::new();
+        for ip in 1..18 {
+            matches.clear();
+            tree.find(&data, ip, data.len(), 0, &mut matches);
+        }
+        // The earlier "abcdeY" is the longest match at 18.
+        matches.clear();
+        tree.find(&data, 18, data.len(), 0, &mut matches);
+        assert_eq!(matches.last(), Some(&(6, 13)));
+        assert!(matches.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(matches.iter().all(|&(len, _)| len >= 3));
+    }
+}
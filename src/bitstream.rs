@@ -0,0 +1,152 @@
+//! Bit streams used by the entropy coders.
+
+use std::convert::TryInto;
+
+/// Writes a bit stream that is meant to be read backwards: bits are packed
+/// from the least significant end of each byte, and the stream is closed
+/// with a single `1` bit so the reader can find where it starts.
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    out: Vec<u8>,
+    container: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// Create an empty bit stream.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append the low `bits` bits of `value` (up to 32 bits).
+    #[inline(always)]
+    pub(crate) fn add(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits <= 32);
+        let mask = (1u64 << bits) - 1;
+        self.container |= (u64::from(value) & mask) << self.bits;
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.out.push(self.container as u8);
+            self.container >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    /// Close the stream and append it to `out`.
+    pub(crate) fn finish(mut self, out: &mut Vec<u8>) {
+        self.add(1, 1);
+        if self.bits != 0 {
+            self.out.push(self.container as u8);
+        }
+        out.extend_from_slice(&self.out);
+    }
+}
+
+/// Reads a bit stream written by [`BitWriter`] backwards, starting with the
+/// last bits written.
+pub(crate) struct BitReader<'a> {
+    data: &'a [u8],
+    /// Bits left to read.
+    bits: usize,
+    /// Whether more bits were read than the stream holds.
+    overflow: bool,
+}
+
+impl<'a> BitReader<'a> {
+    /// Start reading a stream, skipping the closing `1` bit.  Returns `None`
+    /// if the stream is empty or doesn't end with the marker.
+    pub(crate) fn new(data: &'a [u8]) -> Option<Self> {
+        let last = *data.last()?;
+        if last == 0 {
+            return None;
+        }
+        let bits = data.len() * 8 - 1 - last.leading_zeros() as usize;
+        Some(Self { data, bits, overflow: false })
+    }
+
+    /// Look at the next `bits` bits (up to 32) without consuming them.  Bits
+    /// past the start of the stream read as zeros.
+    #[inline(always)]
+    pub(crate) fn peek(&self, bits: u32) -> u32 {
+        if bits == 0 {
+            return 0;
+        }
+        let bits = bits as usize;
+        let start = self.bits.saturating_sub(bits);
+        let byte = start / 8;
+        let value = match self.data.get(byte..byte + 8) {
+            Some(bytes) => u64::from_le_bytes(bytes.try_into().unwrap()),
+            None => {
+                let mut bytes = [0; 8];
+                bytes[..self.data.len() - byte].copy_from_slice(&self.data[byte..]);
+                u64::from_le_bytes(bytes)
+            }
+        };
+        let available = bits.min(self.bits);
+        let value = (value >> (start % 8)) & ((1 << available) - 1);
+        (value << (bits - available)) as u32
+    }
+
+    /// Skip `bits` bits.
+    #[inline(always)]
+    pub(crate) fn consume(&mut self, bits: u32) {
+        let bits = bits as usize;
+        if bits > self.bits {
+            self.overflow = true;
+            self.bits = 0;
+        } else {
+            self.bits -= bits;
+        }
+    }
+
+    /// Read `bits` bits (up to 32).
+    #[inline(always)]
+    pub(crate) fn read(&mut self, bits: u32) -> u32 {
+        let value = self.peek(bits);
+        self.consume(bits);
+        value
+    }
+
+    /// Whether more bits were read than the stream holds.
+    pub(crate) fn overflowed(&self) -> bool {
+        self.overflow
+    }
+
+    /// Whether every bit was read, and no more.
+    pub(crate) fn finished(&self) -> bool {
+        self.bits == 0 && !self.overflow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BitReader, BitWriter};
+
+    #[test]
+    fn padding_marker() {
+        let mut out = Vec::new();
+        let mut writer = BitWriter::new();
+        writer.add(0b101, 3);
+        writer.add(0xFF, 8);
+        writer.finish(&mut out);
+        assert_eq!(out, [0b1111_1101, 0b0000_1111]);
+    }
+
+    #[test]
+    fn read_backwards() {
+        let mut out = Vec::new();
+        let mut writer = BitWriter::new();
+        writer.add(0b101, 3);
+        writer.add(0x1234_5678, 32);
+        writer.add(0b01, 2);
+        writer.finish(&mut out);
+        let mut reader = BitReader::new(&out).unwrap();
+        assert_eq!(reader.read(2), 0b01);
+        assert_eq!(reader.read(32), 0x1234_5678);
+        assert_eq!(reader.peek(5), 0b10100);
+        assert_eq!(reader.read(3), 0b101);
+        assert!(reader.finished());
+        reader.consume(1);
+        assert!(reader.overflowed());
+    }
+}
@@ -0,0 +1,654 @@
+//! Compressed block encoding and decoding.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#compressed-blocks
+
+use super::bitstream::{BitReader, BitWriter};
+use super::dictionary::Dictionary;
+use super::fse::{self, FseDecoder, FseTable};
+use super::huffman::{HuffmanDecoder, HuffmanTable};
+use super::sequence::{self, RepeatOffsets, Sequence};
+use super::DecError;
+
+/// Most accurate literal length table allowed.
+pub(crate) const LL_MAX_LOG: u8 = 9;
+/// Most accurate match length table allowed.
+pub(crate) const ML_MAX_LOG: u8 = 9;
+/// Most accurate offset code table allowed.
+pub(crate) const OF_MAX_LOG: u8 = 8;
+
+/// Fewest literals worth building a Huffman table for.
+const MIN_LITERALS: usize = 64;
+/// Fewest literals worth Huffman coding with the previous block's table.
+const MIN_LITERALS_REUSED: usize = 8;
+
+/// How a literals section is stored.
+#[derive(Copy, Clone)]
+enum LiteralsMode {
+    Raw = 0,
+    Rle = 1,
+    Compressed = 2,
+    Treeless = 3,
+}
+
+/// Symbol compression mode of a sequence code table.
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Predefined = 0,
+    Rle = 1,
+    Compressed = 2,
+    Repeat = 3,
+}
+
+/// State carried from block to block while compressing a frame.
+#[derive(Default, Clone)]
+pub(crate) struct BlockEncoder {
+    reps: RepeatOffsets,
+    /// Literals table the decoder has, for treeless literals.
+    huffman: Option<HuffmanTable>,
+    /// Literal length, offset and match length tables the decoder has, for
+    /// the repeat mode.
+    tables: [Option<(Vec<i16>, u8)>; 3],
+}
+
+impl BlockEncoder {
+    /// Create a block encoder for the start of a frame.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a block encoder partway through a frame, not knowing the
+    /// decoder's state there.  Repeat offsets go unused until it has seen
+    /// matches of its own.
+    pub(crate) fn resumed() -> Self {
+        Self { reps: RepeatOffsets([0; 3]), ..Self::default() }
+    }
+
+    /// Create a block encoder for the start of a frame that uses a
+    /// dictionary, starting from its tables and repeat offsets.
+    pub(crate) fn with_dictionary(dictionary: &Dictionary) -> Self {
+        let mut encoder = Self { reps: dictionary.reps(), ..Self::default() };
+        if let Some(entropy) = dictionary.entropy() {
+            encoder.huffman = Some(entropy.huffman.clone());
+            encoder.tables = entropy.tables.clone().map(Some);
+        }
+        encoder
+    }
+
+    /// Compress a block given its sequences.  Returns `None` (and leaves the
+    /// state untouched) if the result isn't smaller than the input, in
+    /// which case the block should be stored raw.
+    pub(crate) fn compress(&mut self, block: &[u8], sequences: &[Sequence]) -> Option<Vec<u8>> {
+        let literals = literals(block, sequences);
+        let mut out = Vec::with_capacity(block.len());
+        let mut state = self.clone();
+        state.write_literals(&literals, &mut out);
+        write_sequences(sequences, &mut state.reps, &mut state.tables, &mut out);
+
+        if out.len() >= block.len() {
+            return None;
+        }
+        *self = state;
+        Some(out)
+    }
+
+    /// Estimate the size of a compressed block (or the raw size, if that's
+    /// smaller) from its statistics, without encoding it.
+    pub(crate) fn estimate(&self, block: &[u8], sequences: &[Sequence]) -> usize {
+        let literals = literals(block, sequences);
+        let mut counts = [0u32; 256];
+        for &literal in &literals {
+            counts[usize::from(literal)] += 1;
+        }
+        let mut description = Vec::new();
+        let fresh = HuffmanTable::new(&counts)
+            .filter(|table| table.describe(&mut description))
+            .and_then(|table| table.cost(&counts))
+            .map(|cost| cost + description.len());
+        let reused = self.huffman.as_ref().and_then(|table| table.cost(&counts));
+        let literals_size = fresh.into_iter().chain(reused).fold(literals.len(), usize::min);
+
+        let mut bits = 0;
+        if !sequences.is_empty() {
+            let codes = Codes::new(sequences, &mut self.reps.clone());
+            bits += codes.extra_bits();
+            bits += codes.tables(&self.tables).iter().map(|table| table.cost).sum::<usize>();
+        }
+        // Section headers take a few more bytes.
+        (literals_size + bits.div_ceil(8) + 6).min(block.len())
+    }
+
+    /// Write the literals section in the smallest mode: a single repeated
+    /// byte, Huffman coded with a new table or the previous one, or raw
+    /// when Huffman coding saves too little to be worth decoding.
+    fn write_literals(&mut self, literals: &[u8], out: &mut Vec<u8>) {
+        if literals.len() > 1 && literals.iter().all(|&literal| literal == literals[0]) {
+            write_literals_header(LiteralsMode::Rle, literals.len(), out);
+            out.push(literals[0]);
+            return;
+        }
+        // Too few literals to pay for a table, unless there's one already.
+        let min_size = if self.huffman.is_some() { MIN_LITERALS_REUSED } else { MIN_LITERALS };
+        if literals.len() >= min_size {
+            if let Some((table, body)) = self.huffman_literals(literals) {
+                let (regenerated, compressed) = (literals.len() as u64, body.len() as u64);
+                let four_streams = literals.len() >= 256;
+                let (size_format, bits) = match regenerated.max(compressed) {
+                    _ if !four_streams => (0, 10),
+                    0..=1023 => (1, 10),
+                    1024..=16383 => (2, 14),
+                    _ => (3, 18),
+                };
+                let header_len = (4 + bits * 2 + 7) as usize / 8;
+                // Worth it when it saves a little more than the raw header.
+                let min_gain = (literals.len() >> 6) + 2;
+                if header_len + body.len() + min_gain < literals.len() {
+                    let kind = match table {
+                        Some(_) => LiteralsMode::Compressed,
+                        None => LiteralsMode::Treeless,
+                    } as u64;
+                    let header =
+                        kind | size_format << 2 | regenerated << 4 | compressed << (4 + bits);
+                    out.extend_from_slice(&header.to_le_bytes()[..header_len]);
+                    out.extend_from_slice(&body);
+                    if let Some(table) = table {
+                        self.huffman = Some(table);
+                    }
+                    return;
+                }
+            }
+        }
+        write_literals_header(LiteralsMode::Raw, literals.len(), out);
+        out.extend_from_slice(literals);
+    }
+
+    /// Huffman code the literals with a new table or the previous one,
+    /// whichever is smaller.  Returns the new table, if that's the one
+    /// used, and the encoded streams (after the table's description).
+    fn huffman_literals(&self, literals: &[u8]) -> Option<(Option<HuffmanTable>, Vec<u8>)> {
+        let mut counts = [0u32; 256];
+        for &literal in literals {
+            counts[usize::from(literal)] += 1;
+        }
+        let mut description = Vec::new();
+        let fresh = HuffmanTable::new(&counts).filter(|table| table.describe(&mut description));
+        let fresh_cost = fresh.as_ref().and_then(|table| table.cost(&counts));
+        let fresh_cost = fresh_cost.map(|cost| cost + description.len());
+        let reused_cost = self.huffman.as_ref().and_then(|table| table.cost(&counts));
+        let (table, mut body) = match (fresh, fresh_cost, reused_cost) {
+            (Some(table), Some(fresh), Some(reused)) if fresh < reused => {
+                (Some(table), description)
+            }
+            (_, _, Some(_)) => (None, Vec::new()),
+            (Some(table), Some(_), None) => (Some(table), description),
+            _ => return None,
+        };
+
+        let encoder = table.as_ref().or(self.huffman.as_ref())?;
+        if literals.len() >= 256 {
+            if !encoder.encode4(literals, &mut body) {
+                return None;
+            }
+        } else {
+            encoder.encode(literals, &mut body);
+        }
+        Some((table, body))
+    }
+}
+
+/// Write the header of a raw or RLE literals section.
+fn write_literals_header(mode: LiteralsMode, size: usize, out: &mut Vec<u8>) {
+    let (mode, size) = (mode as u32, size as u32);
+    match size {
+        0..=31 => out.push((mode | size << 3) as u8),
+        32..=4095 => out.extend_from_slice(&(mode | 0b0100 | size << 4).to_le_bytes()[..2]),
+        _ => out.extend_from_slice(&(mode | 0b1100 | size << 4).to_le_bytes()[..3]),
+    }
+}
+
+/// The literals of a block: everything its sequences don't copy.
+fn literals(block: &[u8], sequences: &[Sequence]) -> Vec<u8> {
+    let mut literals = Vec::with_capacity(block.len());
+    let mut pos = 0;
+    for seq in sequences {
+        literals.extend_from_slice(&block[pos..][..seq.lit_len as usize]);
+        pos += (seq.lit_len + seq.match_len) as usize;
+    }
+    literals.extend_from_slice(&block[pos..]);
+    literals
+}
+
+/// Literal length, match length and offset codes of each sequence, with
+/// their extra bits, and how often each code is used.
+struct Codes {
+    codes: Vec<[(u8, u8, u32); 3]>,
+    ll_counts: [u32; sequence::LL_CODES.len()],
+    ml_counts: [u32; sequence::ML_CODES.len()],
+    of_counts: [u32; 32],
+}
+
+impl Codes {
+    /// Get the codes of `sequences`, updating the repeat offsets.
+    fn new(sequences: &[Sequence], reps: &mut RepeatOffsets) -> Self {
+        let mut codes = Self {
+            codes: Vec::with_capacity(sequences.len()),
+            ll_counts: [0; sequence::LL_CODES.len()],
+            ml_counts: [0; sequence::ML_CODES.len()],
+            of_counts: [0; 32],
+        };
+        for seq in sequences {
+            let offset_value = reps.encode(seq.lit_len, seq.offset);
+            let ll = sequence::ll_code(seq.lit_len);
+            let ml = sequence::ml_code(seq.match_len);
+            let of = sequence::of_code(offset_value);
+            codes.ll_counts[usize::from(ll.0)] += 1;
+            codes.ml_counts[usize::from(ml.0)] += 1;
+            codes.of_counts[usize::from(of.0)] += 1;
+            codes.codes.push([ll, ml, of]);
+        }
+        codes
+    }
+
+    /// Total number of extra bits.
+    fn extra_bits(&self) -> usize {
+        self.codes.iter().flatten().map(|code| usize::from(code.1)).sum()
+    }
+
+    /// Pick the literal length, offset and match length tables, given the
+    /// `previous` ones the decoder has.
+    fn tables(&self, previous: &[Option<(Vec<i16>, u8)>; 3]) -> [CodeTable; 3] {
+        let nb_seq = self.codes.len();
+        [
+            CodeTable::choose(&self.ll_counts, fse::LL_DEFAULT, &previous[0], LL_MAX_LOG, nb_seq),
+            CodeTable::choose(&self.of_counts, fse::OF_DEFAULT, &previous[1], OF_MAX_LOG, nb_seq),
+            CodeTable::choose(&self.ml_counts, fse::ML_DEFAULT, &previous[2], ML_MAX_LOG, nb_seq),
+        ]
+    }
+}
+
+/// A code table chosen for one kind of sequence code.
+struct CodeTable {
+    mode: Mode,
+    norm: Vec<i16>,
+    log: u8,
+    /// Estimated size in bits of the codes and the table description.
+    cost: usize,
+}
+
+impl CodeTable {
+    /// Pick the cheapest way to describe the codes in `counts`, which may
+    /// be to repeat the `previous` table.
+    fn choose(
+        counts: &[u32],
+        default: (&[i16], u8),
+        previous: &Option<(Vec<i16>, u8)>,
+        max_log: u8,
+        nb_seq: usize,
+    ) -> Self {
+        let max_symbol = counts.iter().rposition(|&c| c != 0).unwrap();
+        if counts[max_symbol] as usize == nb_seq {
+            let mut norm = vec![0; max_symbol + 1];
+            norm[max_symbol] = 1;
+            return Self { mode: Mode::Rle, norm, log: 0, cost: 8 };
+        }
+
+        let log = fse::optimal_log(max_log, nb_seq, max_symbol);
+        let norm = fse::normalize(&counts[..=max_symbol], log);
+        let mut header = Vec::new();
+        fse::write_header(&norm, log, &mut header);
+        let compressed = fse::cost(&norm, log, counts).unwrap() + header.len() * 8;
+        let predefined = fse::cost(default.0, default.1, counts).unwrap_or(usize::MAX);
+        let repeated = previous
+            .as_ref()
+            .and_then(|(norm, log)| fse::cost(norm, *log, counts))
+            .unwrap_or(usize::MAX);
+        if repeated <= predefined.min(compressed) {
+            let (norm, log) = previous.clone().unwrap();
+            Self { mode: Mode::Repeat, norm, log, cost: repeated }
+        } else if predefined <= compressed {
+            let norm = default.0.to_vec();
+            Self { mode: Mode::Predefined, norm, log: default.1, cost: predefined }
+        } else {
+            Self { mode: Mode::Compressed, norm, log, cost: compressed }
+        }
+    }
+
+    /// Write the table description, if the mode has one.
+    fn write(&self, out: &mut Vec<u8>) {
+        match self.mode {
+            Mode::Predefined | Mode::Repeat => {}
+            Mode::Rle => out.push((self.norm.len() - 1) as u8),
+            Mode::Compressed => fse::write_header(&self.norm, self.log, out),
+        }
+    }
+
+    /// Build the encoding table.
+    fn table(&self) -> FseTable {
+        FseTable::new(&self.norm, self.log)
+    }
+}
+
+/// Write the sequences section, updating the repeat offsets and the tables
+/// the decoder will have.
+fn write_sequences(
+    sequences: &[Sequence],
+    reps: &mut RepeatOffsets,
+    tables: &mut [Option<(Vec<i16>, u8)>; 3],
+    out: &mut Vec<u8>,
+) {
+    let nb_seq = sequences.len();
+    match nb_seq {
+        0..=127 => out.push(nb_seq as u8),
+        128..=0x7EFF => out.extend_from_slice(&[(nb_seq >> 8) as u8 + 128, nb_seq as u8]),
+        _ => {
+            let rest = (nb_seq - 0x7F00) as u16;
+            out.push(255);
+            out.extend_from_slice(&rest.to_le_bytes());
+        }
+    }
+    if nb_seq == 0 {
+        return;
+    }
+
+    let codes = Codes::new(sequences, reps);
+    let [ll, of, ml] = codes.tables(tables);
+    out.push((ll.mode as u8) << 6 | (of.mode as u8) << 4 | (ml.mode as u8) << 2);
+    ll.write(out);
+    of.write(out);
+    ml.write(out);
+    // Keep the tables for the repeat mode (RLE ones aren't worth it).
+    for (slot, table) in tables.iter_mut().zip([&ll, &of, &ml]) {
+        *slot = match table.mode {
+            Mode::Rle => None,
+            _ => Some((table.norm.clone(), table.log)),
+        };
+    }
+
+    let (ll_table, of_table, ml_table) = (ll.table(), of.table(), ml.table());
+    let mut writer = BitWriter::new();
+    let [last_ll, last_ml, last_of] = codes.codes[nb_seq - 1];
+    let mut ml_state = ml_table.start(last_ml.0);
+    let mut of_state = of_table.start(last_of.0);
+    let mut ll_state = ll_table.start(last_ll.0);
+    writer.add(last_ll.2, last_ll.1.into());
+    writer.add(last_ml.2, last_ml.1.into());
+    writer.add(last_of.2, last_of.1.into());
+    for &[ll, ml, of] in codes.codes[..nb_seq - 1].iter().rev() {
+        of_state.encode(&mut writer, of.0);
+        ml_state.encode(&mut writer, ml.0);
+        ll_state.encode(&mut writer, ll.0);
+        writer.add(ll.2, ll.1.into());
+        writer.add(ml.2, ml.1.into());
+        writer.add(of.2, of.1.into());
+    }
+    ml_state.flush(&mut writer);
+    of_state.flush(&mut writer);
+    ll_state.flush(&mut writer);
+    writer.finish(out);
+}
+
+/// State carried from block to block while decoding a frame.
+#[derive(Default)]
+pub(crate) struct BlockDecoder {
+    reps: RepeatOffsets,
+    /// Literals table, for treeless literals.
+    huffman: Option<HuffmanDecoder>,
+    /// Literal length, offset and match length tables, for the repeat mode.
+    tables: [Option<FseDecoder>; 3],
+}
+
+impl BlockDecoder {
+    /// Create a block decoder for the start of a frame.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a block decoder for the start of a frame that uses a
+    /// dictionary, starting from its tables and repeat offsets.
+    pub(crate) fn with_dictionary(dictionary: &Dictionary) -> Self {
+        let mut decoder = Self { reps: dictionary.reps(), ..Self::default() };
+        if let Some(entropy) = dictionary.entropy() {
+            decoder.huffman = Some(entropy.huffman_decoder.clone());
+            decoder.tables = entropy.decoders();
+        }
+        decoder
+    }
+
+    /// Decode a compressed block, appending it to `out`, which holds
+    /// everything decoded before it that matches may reference.
+    pub(crate) fn decode(
+        &mut self,
+        block: &[u8],
+        out: &mut Vec<u8>,
+        limit: usize,
+    ) -> Result<(), DecError> {
+        let mut literals = Vec::new();
+        let used = self.read_literals(block, &mut literals, limit)?;
+        self.read_sequences(&block[used..], &literals, out, limit)
+    }
+
+    /// Decode the literals section: bytes read.
+    fn read_literals(
+        &mut self,
+        block: &[u8],
+        literals: &mut Vec<u8>,
+        limit: usize,
+    ) -> Result<usize, DecError> {
+        let header = |len: usize| -> Result<u64, DecError> {
+            let bytes = block.get(..len).ok_or(DecError::Literals)?;
+            Ok(bytes.iter().rev().fold(0, |value, &byte| value << 8 | u64::from(byte)))
+        };
+        let first = *block.first().ok_or(DecError::Literals)?;
+        let (kind, size_format) = (first & 3, first >> 2 & 3);
+
+        if kind < 2 {
+            // Raw or RLE literals.
+            let (len, size) = match size_format {
+                0 | 2 => (1, header(1)? >> 3),
+                1 => (2, header(2)? >> 4),
+                _ => (3, header(3)? >> 4),
+            };
+            let size = size as usize;
+            if size > limit {
+                return Err(DecError::BlockSize);
+            }
+            if kind == 0 {
+                literals.extend_from_slice(block.get(len..len + size).ok_or(DecError::Literals)?);
+                return Ok(len + size);
+            }
+            let byte = *block.get(len).ok_or(DecError::Literals)?;
+            literals.resize(size, byte);
+            return Ok(len + 1);
+        }
+
+        // Huffman coded literals, with a new table or the previous one.
+        let (len, bits) = match size_format {
+            0 | 1 => (3, 10),
+            2 => (4, 14),
+            _ => (5, 18),
+        };
+        let value = header(len)? >> 4;
+        let regenerated = (value & ((1 << bits) - 1)) as usize;
+        let compressed = (value >> bits) as usize;
+        if regenerated > limit {
+            return Err(DecError::BlockSize);
+        }
+        let mut data = block.get(len..len + compressed).ok_or(DecError::Literals)?;
+        if kind == 2 {
+            let (table, used) = HuffmanDecoder::read(data)?;
+            self.huffman = Some(table);
+            data = &data[used..];
+        }
+        let table = self.huffman.as_ref().ok_or(DecError::Literals)?;
+        literals.reserve(regenerated);
+        if size_format == 0 {
+            table.decode(data, regenerated, literals)?;
+        } else {
+            table.decode4(data, regenerated, literals)?;
+        }
+        Ok(len + compressed)
+    }
+
+    /// Decode the sequences section and execute the sequences, failing
+    /// before the block grows past `limit` bytes.
+    fn read_sequences(
+        &mut self,
+        data: &[u8],
+        literals: &[u8],
+        out: &mut Vec<u8>,
+        limit: usize,
+    ) -> Result<(), DecError> {
+        let block_start = out.len();
+        let byte = |pos: usize| data.get(pos).cloned().map(usize::from).ok_or(DecError::Sequences);
+        let (nb_seq, mut pos) = match byte(0)? {
+            first @ 0..=127 => (first, 1),
+            first @ 128..=254 => ((first - 128) << 8 | byte(1)?, 2),
+            _ => (byte(1)? + (byte(2)? << 8) + 0x7F00, 3),
+        };
+        if nb_seq == 0 {
+            out.extend_from_slice(literals);
+            return Ok(());
+        }
+
+        let modes = byte(pos)?;
+        pos += 1;
+        if modes & 3 != 0 {
+            return Err(DecError::Sequences);
+        }
+        let kinds = [
+            (modes >> 6, fse::LL_DEFAULT, sequence::LL_CODES.len(), LL_MAX_LOG),
+            (modes >> 4 & 3, fse::OF_DEFAULT, 32, OF_MAX_LOG),
+            (modes >> 2 & 3, fse::ML_DEFAULT, sequence::ML_CODES.len(), ML_MAX_LOG),
+        ];
+        for (slot, &(mode, default, max_symbols, max_log)) in self.tables.iter_mut().zip(&kinds) {
+            let table = match mode {
+                0 => FseDecoder::new(default.0, default.1),
+                1 => {
+                    let symbol = byte(pos)?;
+                    pos += 1;
+                    if symbol >= max_symbols {
+                        return Err(DecError::Sequences);
+                    }
+                    FseDecoder::rle(symbol as u8)
+                }
+                2 => {
+                    let rest = data.get(pos..).ok_or(DecError::Sequences)?;
+                    let (norm, log, used) = fse::read_header(rest, max_symbols, max_log)?;
+                    pos += used;
+                    FseDecoder::new(&norm, log)
+                }
+                _ => slot.take().ok_or(DecError::Sequences)?,
+            };
+            *slot = Some(table);
+        }
+
+        let [ll, of, ml] = match &self.tables {
+            [Some(ll), Some(of), Some(ml)] => [ll, of, ml],
+            _ => unreachable!(),
+        };
+        let stream = data.get(pos..).ok_or(DecError::Sequences)?;
+        let mut reader = BitReader::new(stream).ok_or(DecError::Sequences)?;
+        let mut ll_state = ll.start(&mut reader);
+        let mut of_state = of.start(&mut reader);
+        let mut ml_state = ml.start(&mut reader);
+        let mut literals = literals;
+
+        for i in 0..nb_seq {
+            let of_code = of.symbol(of_state);
+            let (ml_base, ml_bits) = sequence::ML_CODES[usize::from(ml.symbol(ml_state))];
+            let (ll_base, ll_bits) = sequence::LL_CODES[usize::from(ll.symbol(ll_state))];
+            if of_code > 31 {
+                return Err(DecError::Sequences);
+            }
+            let offset_value = (1 << of_code) + reader.read(of_code.into());
+            let match_len = ml_base + reader.read(ml_bits.into());
+            let lit_len = ll_base + reader.read(ll_bits.into());
+            if i + 1 != nb_seq {
+                ll_state = ll.update(ll_state, &mut reader);
+                ml_state = ml.update(ml_state, &mut reader);
+                of_state = of.update(of_state, &mut reader);
+            }
+            // Sequences past the end of the stream read as zeros.
+            if reader.overflowed() {
+                return Err(DecError::Sequences);
+            }
+            let offset = self.reps.decode(lit_len, offset_value) as usize;
+
+            let lit_len = lit_len as usize;
+            if lit_len > literals.len() {
+                return Err(DecError::Sequences);
+            }
+            if out.len() - block_start + lit_len + match_len as usize > limit {
+                return Err(DecError::BlockSize);
+            }
+            out.extend_from_slice(&literals[..lit_len]);
+            literals = &literals[lit_len..];
+            if offset == 0 || offset > out.len() {
+                return Err(DecError::Offset);
+            }
+            let start = out.len() - offset;
+            let match_len = match_len as usize;
+            if offset >= match_len {
+                out.extend_from_within(start..start + match_len);
+            } else {
+                out.reserve(match_len);
+                for i in start..start + match_len {
+                    out.push(out[i]);
+                }
+            }
+        }
+        if !reader.finished() {
+            return Err(DecError::Sequences);
+        }
+        if out.len() - block_start + literals.len() > limit {
+            return Err(DecError::BlockSize);
+        }
+        out.extend_from_slice(literals);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockEncoder;
+    use crate::sequence::Sequence;
+    use crate::tests::random;
+
+    /// Skewed letters, or noise.
+    fn letters(len: usize, seed: u32, skewed: bool) -> Vec<u8> {
+        let common = b"eeeeettaaoinshrdlu";
+        random(seed)
+            .take(len)
+            .map(|state| state >> 16)
+            .map(|next| if skewed { common[next as usize % 18] } else { next as u8 })
+            .collect()
+    }
+
+    /// Type of the literals section of a compressed block.
+    fn literals_mode(blocks: &mut BlockEncoder, block: &[u8], sequences: &[Sequence]) -> u8 {
+        blocks.compress(block, sequences).unwrap()[0] & 0b11
+    }
+
+    #[test]
+    fn literal_modes() {
+        let mut blocks = BlockEncoder::new();
+        // A new table, then reusing it for the same kind of literals, even
+        // just a few.
+        assert_eq!(literals_mode(&mut blocks, &letters(2000, 1, true), &[]), 2);
+        assert_eq!(literals_mode(&mut blocks, &letters(300, 2, true), &[]), 3);
+        assert_eq!(literals_mode(&mut blocks, &letters(20, 3, true), &[]), 3);
+
+        // One repeated byte.
+        let matched = [Sequence { lit_len: 4, match_len: 40, offset: 4 }];
+        assert_eq!(literals_mode(&mut BlockEncoder::new(), &[b'q'; 44], &matched), 1);
+
+        // Noise and a few letters aren't worth a table.
+        let mut noise = letters(200, 4, false);
+        noise.extend_from_within(..);
+        let matched = [Sequence { lit_len: 200, match_len: 200, offset: 200 }];
+        assert_eq!(literals_mode(&mut BlockEncoder::new(), &noise, &matched), 0);
+        let mut few = letters(40, 5, true);
+        few.extend_from_within(..);
+        let matched = [Sequence { lit_len: 40, match_len: 40, offset: 40 }];
+        assert_eq!(literals_mode(&mut BlockEncoder::new(), &few, &matched), 0);
+    }
+}
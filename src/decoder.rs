@@ -1,46 +1,79 @@
 use std::io::{Read, Error as IoError};
 
-use super::Frame;
+use super::{Dictionary, Frame};
+
+/// Largest window accepted by default (128 MiB), the same as zstd.
+const WINDOW_LOG_DEFAULT: u8 = 27;
 
 /// A ZStandard Stream Decoder that implements [`Read`](std::io::Read)
-pub struct Decoder<R: Read>(R, Frame, usize);
+pub struct Decoder<R: Read> {
+    reader: R,
+    frame: Frame,
+    window_log_max: u8,
+    dictionary: Option<Dictionary>,
+}
 
 impl<R: Read> Decoder<R> {
     /// Create a new ZStandard stream decoder that reads from a `Read`er.
     pub fn new(reader: R) -> Self {
-        Self(reader, Frame::default(), 0)
+        Self {
+            reader,
+            frame: Frame::default(),
+            window_log_max: WINDOW_LOG_DEFAULT,
+            dictionary: None,
+        }
+    }
+
+    /// Set the largest window a frame may use to `1 << log` bytes (10 to
+    /// 31), which limits how much memory decoding takes.  Frames with a
+    /// larger window fail to decode.  The default is 27 (128 MiB); frames
+    /// written with long distance matching and a larger window need this
+    /// raised to match.
+    pub fn window_log_max(mut self, log: u8) -> Self {
+        self.window_log_max = log.clamp(10, 31);
+        self
+    }
+
+    /// Decode frames made with a dictionary.  Frames that record a
+    /// different dictionary ID fail to decode.
+    pub fn with_dictionary(mut self, dictionary: &Dictionary) -> Self {
+        self.dictionary = Some(dictionary.clone());
+        self
+    }
+
+    /// Decode frames made with [`Encoder::ref_prefix`](crate::Encoder::ref_prefix)
+    /// and the same `prefix`.  Their window covers the prefix, so
+    /// [`window_log_max`](Self::window_log_max) may need raising for large
+    /// ones.  This replaces any dictionary.
+    pub fn ref_prefix(mut self, prefix: &[u8]) -> Self {
+        self.dictionary = Some(Dictionary::raw(prefix));
+        self
     }
 }
 
 impl<R: Read> Read for Decoder<R> {
-    fn read(&mut self, mut buf: &mut [u8]) -> Result<usize, IoError> {
-        // Entire length of the buffer.
-        let orig_len = buf.len();
-        // Fill up the buffer until there are no remaining bytes.
-        while !buf.is_empty() {
-            // Check if there is no frame data, then decode next frame.
-            if self.1.data[self.2..].is_empty() {
-                // Try to decode the next frame.
-                self.1.decode(&mut self.0)?;
-                // If still empty, return early with partially filled buffer.
-                if self.1.data[self.2..].is_empty() {
-                    return Ok(orig_len - buf.len());
-                }
+    /// Decodes a block at a time, so bytes are returned as soon as the
+    /// block holding them arrives, even partway through a frame.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.frame.unread().is_empty() {
+            if self.frame.active {
+                self.frame.decode_block(&mut self.reader)?;
+                continue;
             }
-            // Get the frame data
-            let data = &self.1.data[self.2..];
-            // Check for number of bytes from previous frame.
-            let amt_to_copy = data.len().min(buf.len());
-            // Copy bytes
-            for i in 0..amt_to_copy {
-                buf[i] = data[i];
+            // Decode the next frame's header, stopping at the end of the
+            // input.
+            let dictionary = self.dictionary.as_ref();
+            if !self.frame.start(&mut self.reader, self.window_log_max, dictionary)? {
+                return Ok(0);
             }
-            // Move buffer index.
-            self.2 += amt_to_copy;
-            // Shrink writeable slice of out buffer.
-            buf = &mut buf[amt_to_copy..];
         }
-        // Successfully filled up entire buffer.
-        Ok(orig_len)
+        let data = self.frame.unread();
+        let amt_to_copy = data.len().min(buf.len());
+        buf[..amt_to_copy].copy_from_slice(&data[..amt_to_copy]);
+        self.frame.pos += amt_to_copy;
+        Ok(amt_to_copy)
     }
 }
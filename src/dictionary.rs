@@ -0,0 +1,239 @@
+//! Dictionaries: content that frames can refer back to, plus entropy tables
+//! and repeat offsets to start from.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#dictionary-format
+
+use std::io::Error as IoError;
+use std::sync::Arc;
+
+use super::block::{LL_MAX_LOG, ML_MAX_LOG, OF_MAX_LOG};
+use super::fse::{self, FseDecoder};
+use super::huffman::{HuffmanDecoder, HuffmanTable};
+use super::sequence::{self, RepeatOffsets};
+use super::DecError;
+
+/// Magic number at the start of a dictionary with entropy tables.
+const MAGIC_NUMBER: u32 = 0xEC30A437;
+
+/// Entropy tables stored in a dictionary, for both directions.
+pub(crate) struct Entropy {
+    pub(crate) huffman: HuffmanTable,
+    pub(crate) huffman_decoder: HuffmanDecoder,
+    /// Literal length, offset and match length normalized counts and logs.
+    pub(crate) tables: [(Vec<i16>, u8); 3],
+}
+
+impl Entropy {
+    /// Decoding tables for literal lengths, offsets and match lengths.
+    pub(crate) fn decoders(&self) -> [Option<FseDecoder>; 3] {
+        let [ll, of, ml] = &self.tables;
+        [ll, of, ml].map(|(norm, log)| Some(FseDecoder::new(norm, *log)))
+    }
+}
+
+struct Parsed {
+    id: u32,
+    content: Vec<u8>,
+    reps: RepeatOffsets,
+    entropy: Option<Entropy>,
+}
+
+/// A zstd dictionary, shared by [`Encoder`](crate::Encoder)s and
+/// [`Decoder`](crate::Decoder)s.  Cloning is cheap.
+#[derive(Clone)]
+pub struct Dictionary(Arc<Parsed>);
+
+impl Dictionary {
+    /// Load a dictionary made by `zstd --train`, or use any other data as a
+    /// raw content dictionary (which has no ID).
+    pub fn new(data: &[u8]) -> Result<Self, IoError> {
+        if data.len() < 8 || data[..4] != MAGIC_NUMBER.to_le_bytes() {
+            return Ok(Self::raw(data));
+        }
+        let id = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        let mut rest = &data[8..];
+
+        let (huffman, used) = HuffmanTable::read(rest).map_err(|_| DecError::Dictionary)?;
+        let (huffman_decoder, _) = HuffmanDecoder::read(rest).map_err(|_| DecError::Dictionary)?;
+        rest = &rest[used..];
+        // Stored as offsets, match lengths, then literal lengths.
+        let mut read_table = |max_symbols, max_log| {
+            let (norm, log, used) =
+                fse::read_header(rest, max_symbols, max_log).map_err(|_| DecError::Dictionary)?;
+            rest = &rest[used..];
+            Ok::<_, DecError>((norm, log))
+        };
+        let of = read_table(32, OF_MAX_LOG)?;
+        let ml = read_table(sequence::ML_CODES.len(), ML_MAX_LOG)?;
+        let ll = read_table(sequence::LL_CODES.len(), LL_MAX_LOG)?;
+
+        if rest.len() < 12 {
+            Err(DecError::Dictionary)?
+        }
+        let mut reps = [0; 3];
+        for (rep, bytes) in reps.iter_mut().zip(rest.chunks(4)) {
+            *rep = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let content = rest[12..].to_vec();
+        if reps.iter().any(|&rep| rep == 0 || rep as usize > content.len()) {
+            Err(DecError::Dictionary)?
+        }
+
+        Ok(Self(Arc::new(Parsed {
+            id,
+            content,
+            reps: RepeatOffsets(reps),
+            entropy: Some(Entropy { huffman, huffman_decoder, tables: [ll, of, ml] }),
+        })))
+    }
+
+    /// Use `content` as a raw content dictionary, even if it looks like a
+    /// dictionary with entropy tables.
+    pub(crate) fn raw(content: &[u8]) -> Self {
+        Self(Arc::new(Parsed {
+            id: 0,
+            content: content.to_vec(),
+            reps: RepeatOffsets::default(),
+            entropy: None,
+        }))
+    }
+
+    /// The dictionary ID written in frame headers, or 0 for none.
+    pub fn id(&self) -> u32 {
+        self.0.id
+    }
+
+    /// Content that matches can refer to.
+    pub(crate) fn content(&self) -> &[u8] {
+        &self.0.content
+    }
+
+    /// Repeat offsets to start each frame with.
+    pub(crate) fn reps(&self) -> RepeatOffsets {
+        self.0.reps
+    }
+
+    /// Entropy tables to start each frame with, unless the dictionary is
+    /// raw content.
+    pub(crate) fn entropy(&self) -> Option<&Entropy> {
+        self.0.entropy.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dictionary, MAGIC_NUMBER};
+    use crate::fse;
+    use crate::huffman::HuffmanTable;
+    use crate::params::CompressionParams;
+    use crate::{Decoder, Encoder};
+    use std::io::{Read, Write};
+
+    /// A message like the dictionary content, but not the same.
+    fn message(i: u32) -> Vec<u8> {
+        format!(r#"{{"id":{},"user":"user{}","active":true,"tags":["a","b"]}}"#, i, i % 7)
+            .into_bytes()
+    }
+
+    /// A dictionary with tables built from its content.
+    fn trained(id: u32) -> Vec<u8> {
+        let content: Vec<u8> = (0..20).flat_map(message).collect();
+        let mut counts = [0; 256];
+        content.iter().for_each(|&byte| counts[usize::from(byte)] += 1);
+
+        let mut dictionary = MAGIC_NUMBER.to_le_bytes().to_vec();
+        dictionary.extend_from_slice(&id.to_le_bytes());
+        assert!(HuffmanTable::new(&counts).unwrap().describe(&mut dictionary));
+        for (norm, log) in [fse::OF_DEFAULT, fse::ML_DEFAULT, fse::LL_DEFAULT] {
+            fse::write_header(norm, log, &mut dictionary);
+        }
+        for rep in [1u32, 4, 8] {
+            dictionary.extend_from_slice(&rep.to_le_bytes());
+        }
+        dictionary.extend_from_slice(&content);
+        dictionary
+    }
+
+    fn compress(data: &[u8], dictionary: &Dictionary) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = Encoder::new(&mut out).with_dictionary(dictionary);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap();
+        out
+    }
+
+    fn decompress(frame: &[u8], dictionary: &Dictionary) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Decoder::new(frame).with_dictionary(dictionary).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = message(1234);
+        let plain = crate::tests::compress(&data, Default::default());
+        for &(id, flag) in &[(5, 1), (300, 2), (70_000, 3)] {
+            let dictionary = Dictionary::new(&trained(id)).unwrap();
+            assert_eq!(dictionary.id(), id);
+            let frame = compress(&data, &dictionary);
+            // Single segment, with the smallest dictionary ID field.
+            assert_eq!(frame[4] & 0b0010_0011, 0b0010_0000 | flag);
+            assert!(frame.len() < plain.len() / 2);
+            assert_eq!(decompress(&frame, &dictionary).unwrap(), data);
+
+            // Another dictionary doesn't fit.
+            let other = Dictionary::new(&trained(id + 1)).unwrap();
+            assert!(decompress(&frame, &other).is_err());
+        }
+    }
+
+    #[test]
+    fn raw_content() {
+        let content: Vec<u8> = (0..20).flat_map(message).collect();
+        let dictionary = Dictionary::new(&content).unwrap();
+        assert_eq!(dictionary.id(), 0);
+        let data = message(1234);
+        let frame = compress(&data, &dictionary);
+        assert_eq!(frame[4] & 0b11, 0);
+        assert_eq!(decompress(&frame, &dictionary).unwrap(), data);
+    }
+
+    #[test]
+    fn larger_than_the_window() {
+        // A single segment frame's window is just the message, but the
+        // decoder keeps the whole dictionary.
+        let content: Vec<u8> = (0..6000).flat_map(message).collect();
+        assert!(content.len() > 300_000);
+        let dictionary = Dictionary::new(&content).unwrap();
+        // The start of the dictionary is furthest from the window.
+        let mut data = content[..1000].to_vec();
+        data.extend((0..20).flat_map(|i| message(10_000 + i)));
+        assert!(data.len() > 2000);
+        let frame = compress(&data, &dictionary);
+        assert_eq!(decompress(&frame, &dictionary).unwrap(), data);
+    }
+
+    #[test]
+    fn every_strategy() {
+        let content: Vec<u8> = (0..20).flat_map(message).collect();
+        let dictionary = Dictionary::new(&content).unwrap();
+        let data = message(1234);
+        for &level in &[-1, 1, 3, 5, 7, 9, 14, 17, 19, 22] {
+            let plain = crate::tests::compress(&data, CompressionParams::level(level));
+            let mut frame = Vec::new();
+            let mut encoder = Encoder::new(&mut frame).level(level).with_dictionary(&dictionary);
+            encoder.write_all(&data).unwrap();
+            encoder.finish().unwrap();
+            // The dictionary content is indexed for every match finder.
+            assert!(frame.len() < plain.len() / 2, "level {}", level);
+            assert_eq!(decompress(&frame, &dictionary).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let mut truncated = trained(5);
+        truncated.truncate(40);
+        assert!(Dictionary::new(&truncated).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Double fast match finder: a table of long matches is checked before a
+//! table of short ones.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/compress/zstd_double_fast.c
+
+use super::matcher::{self, MatchFinder, HASH_READ_SIZE};
+use super::params::CompressionParams;
+use super::sequence::Sequence;
+
+/// Bytes hashed for the long table.
+const LONG_MATCH: u8 = 8;
+/// How quickly to skip ahead through data without matches.
+const SEARCH_STRENGTH: u32 = 8;
+
+/// Finds matches by looking up the last position with the same 8 bytes,
+/// falling back to the last position with the same first few bytes.
+pub(crate) struct DoubleFastMatcher {
+    long_table: Vec<u32>,
+    short_table: Vec<u32>,
+    long_log: u8,
+    short_log: u8,
+    min_match: u8,
+    window: usize,
+    rep: [u32; 2],
+}
+
+impl DoubleFastMatcher {
+    /// Create a double fast match finder.
+    pub(crate) fn new(params: &CompressionParams) -> Self {
+        Self {
+            long_table: vec![0; 1 << params.hash_log],
+            short_table: vec![0; 1 << params.chain_log],
+            long_log: params.hash_log,
+            short_log: params.chain_log,
+            min_match: params.min_match.clamp(4, 7),
+            window: params.window(),
+            rep: [1, 4],
+        }
+    }
+
+    /// Whether `candidate` is usable from `ip` and its first `bytes` bytes
+    /// match.
+    #[inline(always)]
+    fn equal(&self, data: &[u8], candidate: usize, ip: usize, bytes: u8) -> bool {
+        let diff = matcher::read_u64(data, candidate) ^ matcher::read_u64(data, ip);
+        candidate < ip && ip - candidate <= self.window && diff << (64 - 8 * u32::from(bytes)) == 0
+    }
+
+    /// Add `pos` to both tables.
+    #[inline(always)]
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        self.long_table[matcher::hash(data, pos, LONG_MATCH, self.long_log)] = pos as u32;
+        self.short_table[matcher::hash(data, pos, self.min_match, self.short_log)] = pos as u32;
+    }
+}
+
+impl MatchFinder for DoubleFastMatcher {
+    fn find(&mut self, data: &[u8], start: usize, end: usize, sequences: &mut Vec<Sequence>) {
+        let limit = end.saturating_sub(HASH_READ_SIZE);
+        let mut anchor = start;
+        let mut ip = start.max(1);
+
+        while ip < limit {
+            let long_hash = matcher::hash(data, ip, LONG_MATCH, self.long_log);
+            let short_hash = matcher::hash(data, ip, self.min_match, self.short_log);
+            let long = self.long_table[long_hash] as usize;
+            let short = self.short_table[short_hash] as usize;
+            self.long_table[long_hash] = ip as u32;
+            self.short_table[short_hash] = ip as u32;
+
+            // Try the most recent offset one position ahead first.
+            let rep = self.rep[0] as usize;
+            let (mut pos, mut len, offset) = if rep <= ip + 1
+                && rep <= self.window
+                && matcher::read_u32(data, ip + 1 - rep) == matcher::read_u32(data, ip + 1)
+            {
+                let len = 4 + matcher::match_len(data, ip + 5 - rep, ip + 5, end);
+                (ip + 1, len, rep)
+            } else if self.equal(data, long, ip, LONG_MATCH) {
+                (ip, matcher::match_len(data, long, ip, end), ip - long)
+            } else if self.equal(data, short, ip, self.min_match) {
+                // A long match one position ahead beats a short one here.
+                let next = ip + 1;
+                let next_hash = matcher::hash(data, next, LONG_MATCH, self.long_log);
+                let long = self.long_table[next_hash] as usize;
+                self.long_table[next_hash] = next as u32;
+                if next < limit && self.equal(data, long, next, LONG_MATCH) {
+                    (next, matcher::match_len(data, long, next, end), next - long)
+                } else {
+                    (ip, matcher::match_len(data, short, ip, end), ip - short)
+                }
+            } else {
+                ip += ((ip - anchor) >> SEARCH_STRENGTH) + 1;
+                continue;
+            };
+
+            // Extend the match backwards over the literals.
+            while pos > anchor && pos > offset && data[pos - 1] == data[pos - 1 - offset] {
+                pos -= 1;
+                len += 1;
+            }
+
+            sequences.push(Sequence {
+                lit_len: (pos - anchor) as u32,
+                match_len: len as u32,
+                offset: offset as u32,
+            });
+            if offset as u32 != self.rep[0] {
+                self.rep = [offset as u32, self.rep[0]];
+            }
+            ip = pos + len;
+            anchor = ip;
+
+            // Fill in some of the skipped positions.
+            for fill in [pos + 2, ip - 2].iter().cloned() {
+                if fill < limit && fill > pos {
+                    self.insert(data, fill);
+                }
+            }
+
+            // Matches with the second most recent offset need no literals.
+            while ip < limit {
+                let rep = self.rep[1] as usize;
+                if rep > ip
+                    || rep > self.window
+                    || matcher::read_u32(data, ip - rep) != matcher::read_u32(data, ip)
+                {
+                    break;
+                }
+                let len = 4 + matcher::match_len(data, ip + 4 - rep, ip + 4, end);
+                sequences.push(Sequence {
+                    lit_len: 0,
+                    match_len: len as u32,
+                    offset: rep as u32,
+                });
+                self.rep.swap(0, 1);
+                self.insert(data, ip);
+                ip += len;
+                anchor = ip;
+            }
+        }
+    }
+
+    fn slide(&mut self, amount: usize) {
+        matcher::slide(&mut self.long_table, amount);
+        matcher::slide(&mut self.short_table, amount);
+    }
+
+    fn fill(&mut self, data: &[u8], start: usize, end: usize) {
+        for pos in start.max(1)..end.saturating_sub(HASH_READ_SIZE) {
+            self.insert(data, pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DoubleFastMatcher, MatchFinder};
+    use crate::matcher::tests::execute;
+    use crate::params::CompressionParams;
+    use crate::row::tests::words;
+
+    #[test]
+    fn sequences_rebuild_input() {
+        let data = words(200_000);
+        for level in 3..=4 {
+            let mut matcher = DoubleFastMatcher::new(&CompressionParams::level(level));
+            let mut sequences = Vec::new();
+            let middle = data.len() / 2;
+            matcher.find(&data, 0, middle, &mut sequences);
+            assert_eq!(execute(&data, 0, middle, &sequences), &data[..middle]);
+            let mut more = Vec::new();
+            matcher.find(&data, middle, data.len(), &mut more);
+            assert_eq!(execute(&data, middle, data.len(), &more), data);
+        }
+    }
+}
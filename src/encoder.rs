@@ -1,49 +1,975 @@
-use std::io::{Write, Error as IoError};
+use std::io::{Write, Error as IoError, ErrorKind};
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
-use super::Frame;
+use super::adapt::{Adapt, Timed};
+use super::block::BlockEncoder;
+use super::dictionary::Dictionary;
+use super::matcher::{self, MatchFinder};
+use super::parallel::ParallelEncoder;
+use super::sequence::Sequence;
+use super::split;
+use super::xxhash::Xxh64;
+use super::{write_block_header, BlockType, CompressionParams, MAGIC_NUMBER};
+
+/// Bytes searched for matches at the start of a block that looks random.
+const PROBE_SIZE: usize = 4 << 10;
+/// A block that looks random is stored raw if less than this fraction of
+/// the probed bytes are in matches.
+const MIN_MATCHED_RATIO: usize = 64;
+/// Bits per byte above which a block looks random: Huffman coding would
+/// save less than the 1/64 that literals need to gain.
+const RANDOM_BITS: f64 = 7.9;
+/// Sample every few bytes when checking whether a block looks random.
+const SAMPLE_STEP: usize = 4;
+/// Most history searched again by the new match finder when the level
+/// changes.
+const REINDEX_SIZE: usize = 1 << 20;
+/// Size of a block header.
+const BLOCK_HEADER_SIZE: usize = 3;
+
+/// Counts of the blocks an [`Encoder`] has written.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct EncoderStats {
+    /// Blocks compressed with matches and entropy coding.
+    pub compressed_blocks: u64,
+    /// Blocks stored as they are, including the incompressible ones.
+    pub raw_blocks: u64,
+    /// Blocks of one repeated byte.
+    pub rle_blocks: u64,
+    /// Raw blocks stored without a full search for matches, because a
+    /// sample of them looked incompressible.
+    pub incompressible_blocks: u64,
+    /// Input bytes in the blocks.
+    pub bytes_in: u64,
+    /// Size of the blocks with their headers (frame headers and checksums
+    /// aside).
+    pub bytes_out: u64,
+}
+
+impl EncoderStats {
+    /// Count a block of `size` bytes of input stored in `stored` bytes.
+    pub(crate) fn add_block(&mut self, block_type: BlockType, size: usize, stored: usize) {
+        match block_type {
+            BlockType::Raw => self.raw_blocks += 1,
+            BlockType::Rle => self.rle_blocks += 1,
+            BlockType::Compressed => self.compressed_blocks += 1,
+        }
+        self.bytes_in += size as u64;
+        self.bytes_out += 3 + stored as u64;
+    }
+
+    /// Add the counts from another encoder.
+    pub(crate) fn add(&mut self, other: &EncoderStats) {
+        self.compressed_blocks += other.compressed_blocks;
+        self.raw_blocks += other.raw_blocks;
+        self.rle_blocks += other.rle_blocks;
+        self.incompressible_blocks += other.incompressible_blocks;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+    }
+}
+
+/// Compresses one frame a block at a time, keeping a window of history for
+/// matches to refer to.
+pub(crate) struct FrameEncoder {
+    params: CompressionParams,
+    matcher: Box<dyn MatchFinder>,
+    blocks: BlockEncoder,
+    sequences: Vec<Sequence>,
+    hasher: Option<Xxh64>,
+    /// History followed by the input not compressed yet.
+    data: Vec<u8>,
+    /// Start of the input not compressed yet.
+    pending: usize,
+    /// Amount of history to drop at a time, a multiple of the matcher's
+    /// table sizes.
+    step: usize,
+    /// Changes the level between blocks, if set.
+    pub(crate) adapt: Option<Adapt>,
+    pub(crate) stats: EncoderStats,
+}
+
+/// Write a frame header.  Returns the parameters to compress the frame
+/// with, adjusted to a known `content_size` so small frames use smaller
+/// tables (and skip the window descriptor).
+pub(crate) fn write_header<W: Write>(
+    writer: &mut W,
+    params: &CompressionParams,
+    content_size: Option<u64>,
+    dictionary: Option<&Dictionary>,
+) -> Result<CompressionParams, IoError> {
+    // The window has to reach back over the dictionary too.
+    let history = dictionary.map_or(0, |dictionary| dictionary.content().len() as u64);
+    let params = content_size.map_or(*params, |size| params.adjust(size + history));
+
+    ///////////////////// Magic_Number ////////////////////
+
+    writer.write_all(&MAGIC_NUMBER.to_le_bytes())?;
+
+    ///////////////////// Frame_Header ////////////////////
+
+    // A single segment frame has no window descriptor: the window is the
+    // whole content.
+    let content_size = content_size.filter(|_| params.content_size);
+    let single_segment = content_size.is_some_and(|size| size <= params.window() as u64);
+    // Frame Content Size (smallest field that fits)
+    let (fcs_flag, fcs, fcs_len) = match content_size {
+        None => (0, 0, 0),
+        Some(size @ 0..=255) if single_segment => (0, size, 1),
+        Some(size @ 256..=65791) => (1, size - 256, 2),
+        Some(size @ 0..=0xFFFF_FFFF) => (2, size, 4),
+        Some(size) => (3, size, 8),
+    };
+    // Dictionary ID (smallest field that fits)
+    let dictionary_id = dictionary.map_or(0, Dictionary::id);
+    let (did_flag, did_len) = match dictionary_id {
+        _ if !params.dictionary_id => (0, 0),
+        0 => (0, 0),
+        1..=0xFF => (1, 1),
+        0x100..=0xFFFF => (2, 2),
+        _ => (3, 4),
+    };
+    let frame_head_desc =
+        fcs_flag << 6 | (single_segment as u8) << 5 | (params.checksum as u8) << 2 | did_flag;
+    writer.write_all(&[frame_head_desc])?;
+    if !single_segment {
+        writer.write_all(&[params.window_descriptor()])?;
+    }
+    writer.write_all(&dictionary_id.to_le_bytes()[..did_len])?;
+    writer.write_all(&fcs.to_le_bytes()[..fcs_len])?;
+    Ok(params)
+}
+
+impl FrameEncoder {
+    /// Write the frame header and get ready for the content.
+    pub(crate) fn new<W: Write>(
+        writer: &mut W,
+        params: &CompressionParams,
+        content_size: Option<u64>,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<Self, IoError> {
+        let params = write_header(writer, params, content_size, dictionary)?;
+        let history = dictionary.map_or(&[][..], Dictionary::content);
+        let blocks = dictionary.map_or_else(BlockEncoder::new, BlockEncoder::with_dictionary);
+        Ok(Self::resume(&params, history, blocks, params.checksum))
+    }
+
+    /// Continue a frame after `history`, which matches may refer to as if
+    /// it came just before the content.  `blocks` has to agree with the
+    /// decoder's state at this point.  Without `checksum`, the checksum is
+    /// left to the caller.
+    pub(crate) fn resume(
+        params: &CompressionParams,
+        history: &[u8],
+        blocks: BlockEncoder,
+        checksum: bool,
+    ) -> Self {
+        let mut matcher = matcher::new(params);
+        matcher.fill(history, 0, history.len());
+
+        Self {
+            params: *params,
+            matcher,
+            blocks,
+            sequences: Vec::new(),
+            hasher: if checksum { Some(Xxh64::new()) } else { None },
+            data: history.to_vec(),
+            pending: history.len(),
+            step: slide_step(params),
+            adapt: None,
+            stats: EncoderStats::default(),
+        }
+    }
+
+    /// Add content, compressing every full block except the last.
+    pub(crate) fn write<W: Write>(&mut self, writer: &mut W, buf: &[u8]) -> Result<(), IoError> {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(buf);
+        }
+        self.data.extend_from_slice(buf);
+        // Hold back a block, in case it's the last one.
+        while self.data.len() - self.pending > self.params.block_size() {
+            self.compress_block(writer, false)?;
+        }
+        Ok(())
+    }
+
+    /// Compress what's left as the last block, then write the checksum.
+    /// Returns the counts for the whole frame.
+    pub(crate) fn finish<W: Write>(mut self, writer: &mut W) -> Result<EncoderStats, IoError> {
+        if self.pending == self.data.len() {
+            write_block_header(writer, true, BlockType::Raw, 0)?;
+            self.stats.add_block(BlockType::Raw, 0, 0);
+        }
+        self.compress_pending(writer, true)?;
+
+        ///////////////////// Content_Checksum ////////////////////
+
+        if let Some(hasher) = &self.hasher {
+            writer.write_all(&(hasher.digest() as u32).to_le_bytes())?;
+        }
+        Ok(self.stats)
+    }
+
+    /// Compress all of the pending input, ending the frame if `last`.
+    pub(crate) fn compress_pending<W: Write>(
+        &mut self,
+        writer: &mut W,
+        last: bool,
+    ) -> Result<(), IoError> {
+        while self.pending < self.data.len() {
+            self.compress_block(writer, last)?;
+        }
+        Ok(())
+    }
+
+    /// Compress the next block of pending input, timing it if the level
+    /// adapts.  `last` marks it as the end of the frame if it takes all of
+    /// the input.
+    fn compress_block<W: Write>(&mut self, writer: &mut W, last: bool) -> Result<(), IoError> {
+        let mut adapt = match self.adapt.take() {
+            Some(adapt) => adapt,
+            None => return self.encode_block(writer, last),
+        };
+        let started = Instant::now();
+        let mut timed = Timed::new(&mut *writer);
+        let result = self.encode_block(&mut timed, last);
+        if let Some(level) = adapt.record(1, started.elapsed(), timed.spent) {
+            self.set_level(level);
+        }
+        self.adapt = Some(adapt);
+        result
+    }
+
+    /// Switch to another level for the blocks to come.  The new match
+    /// finder only indexes the end of the history again, to keep the
+    /// switch cheap.
+    fn set_level(&mut self, level: i32) {
+        self.params = self.params.at_level(level);
+        self.step = slide_step(&self.params);
+        self.matcher = matcher::new(&self.params);
+        // Filling from `from` leaves everything before it out, which also
+        // keeps long distance matching from hashing the whole history.
+        let from = self.pending.saturating_sub(self.params.window().min(REINDEX_SIZE));
+        self.matcher.fill(&self.data, from, self.pending);
+    }
+
+    /// Compress as much of `input` as fits in `budget` bytes of blocks,
+    /// ending the frame there, then write the checksum.  Returns how much
+    /// of `input` the frame holds.
+    pub(crate) fn finish_to_fit<W: Write>(
+        mut self,
+        writer: &mut W,
+        input: &[u8],
+        mut budget: usize,
+    ) -> Result<usize, IoError> {
+        self.data.extend_from_slice(input);
+        let (mut consumed, mut cut) = (0, false);
+        if input.is_empty() || budget <= BLOCK_HEADER_SIZE {
+            write_block_header(writer, true, BlockType::Raw, 0)?;
+            self.stats.add_block(BlockType::Raw, 0, 0);
+        }
+        while consumed < input.len() && budget > BLOCK_HEADER_SIZE {
+            self.slide();
+            let start = self.pending;
+            let end = (start + self.params.block_size()).min(self.data.len());
+            let block = &self.data[start..end];
+            let room = budget - BLOCK_HEADER_SIZE;
+
+            let rle = block.len() > 1 && block.iter().all(|&byte| byte == block[0]);
+            let mut compressed = None;
+            // The match finder has already seen the rest of a block that was
+            // cut, so what's left of it goes raw.
+            if !rle && !cut {
+                self.sequences.clear();
+                self.matcher.find(&self.data, start, end, &mut self.sequences);
+                let (len, count) = split::fit(&self.blocks, block, &mut self.sequences, budget);
+                // Only keep the new tables if the block is written with them.
+                let mut blocks = self.blocks.clone();
+                if let Some(part) = blocks.compress(&block[..len], &self.sequences[..count]) {
+                    if part.len() <= room {
+                        self.blocks = blocks;
+                        compressed = Some((len, part));
+                    }
+                }
+            }
+            let (len, block_type, stored) = match &compressed {
+                _ if rle => (block.len(), BlockType::Rle, 1),
+                Some((len, part)) => (*len, BlockType::Compressed, part.len()),
+                None => (block.len().min(room), BlockType::Raw, block.len().min(room)),
+            };
+            budget -= BLOCK_HEADER_SIZE + stored;
+            // A block that had to be cut is followed by as many of the bytes
+            // after it as fit, until there's no room for another block.
+            cut |= len < block.len();
+            let last = start + len == self.data.len() || budget <= BLOCK_HEADER_SIZE;
+            let size = if rle { len } else { stored };
+            write_block_header(writer, last, block_type, size)?;
+            match &compressed {
+                Some((_, part)) => writer.write_all(part)?,
+                None => writer.write_all(&block[..stored])?,
+            }
+            self.stats.add_block(block_type, len, stored);
+            if let Some(hasher) = &mut self.hasher {
+                hasher.update(&block[..len]);
+            }
+            self.pending += len;
+            consumed += len;
+            if last {
+                break;
+            }
+        }
+
+        ///////////////////// Content_Checksum ////////////////////
+
+        if let Some(hasher) = &self.hasher {
+            writer.write_all(&(hasher.digest() as u32).to_le_bytes())?;
+        }
+        Ok(consumed)
+    }
+
+    /// Drop history that's out of the window.
+    fn slide(&mut self) {
+        let window = self.params.window();
+        if self.pending >= window + self.step {
+            let shift = (self.pending - window) / self.step * self.step;
+            self.data.drain(..shift);
+            self.pending -= shift;
+            self.matcher.slide(shift);
+        }
+    }
+
+    /// Compress the next block of pending input.
+    fn encode_block<W: Write>(&mut self, writer: &mut W, last: bool) -> Result<(), IoError> {
+        self.slide();
+        let start = self.pending;
+        let end = (start + self.params.block_size()).min(self.data.len());
+        let last = last && end == self.data.len();
+        self.pending = end;
+        let block = &self.data[start..end];
+
+        if block.len() > 1 && block.iter().all(|&byte| byte == block[0]) {
+            write_block_header(writer, last, BlockType::Rle, block.len())?;
+            writer.write_all(&block[..1])?;
+            self.stats.add_block(BlockType::Rle, block.len(), 1);
+            return Ok(());
+        }
+
+        // Bytes that look random get a short search first, and are stored
+        // as they are if it finds next to nothing.
+        self.sequences.clear();
+        let mut searched = start;
+        if block.len() >= 2 * PROBE_SIZE && looks_random(block) {
+            searched = start + PROBE_SIZE;
+            self.matcher.find(&self.data, start, searched, &mut self.sequences);
+            let matched: u32 = self.sequences.iter().map(|seq| seq.match_len).sum();
+            if (matched as usize) < PROBE_SIZE / MIN_MATCHED_RATIO {
+                self.matcher.skip(&self.data, start, end);
+                let target = self.params.target_block_size;
+                let count = write_raw(writer, last, block, target, &mut self.stats)?;
+                self.stats.incompressible_blocks += count;
+                return Ok(());
+            }
+        }
+        // The rest of the search takes over the literals left at the end of
+        // the first part.
+        let first = self.sequences.len();
+        let covered: u32 = self.sequences.iter().map(|seq| seq.lit_len + seq.match_len).sum();
+        self.matcher.find(&self.data, searched, end, &mut self.sequences);
+        if let Some(seq) = self.sequences.get_mut(first) {
+            seq.lit_len += (searched - start) as u32 - covered;
+        }
+
+        // A target size cuts blocks as they're written instead.
+        let target = self.params.target_block_size;
+        let parts = if self.params.block_split && target == 0 {
+            split::split(&self.blocks, block, &self.sequences)
+        } else {
+            vec![(block.len(), self.sequences.len())]
+        };
+        let (mut pos, mut first) = (0, 0);
+        for &(part_end, part_last) in &parts {
+            loop {
+                let (end, seq_end) = if target > 0 {
+                    let sequences = &mut self.sequences[first..part_last];
+                    let (len, count) =
+                        split::fit(&self.blocks, &block[pos..part_end], sequences, target);
+                    (pos + len, first + count)
+                } else {
+                    (part_end, part_last)
+                };
+                let (part, sequences) = (&block[pos..end], &self.sequences[first..seq_end]);
+                let last = last && end == block.len();
+                if let Some(compressed) = self.blocks.compress(part, sequences) {
+                    write_block_header(writer, last, BlockType::Compressed, compressed.len())?;
+                    writer.write_all(&compressed)?;
+                    self.stats.add_block(BlockType::Compressed, part.len(), compressed.len());
+                } else {
+                    write_raw(writer, last, part, target, &mut self.stats)?;
+                }
+                pos = end;
+                first = seq_end;
+                if pos == part_end {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Store `data` in raw blocks, cut to the target block size if there is one.
+/// Returns the number of blocks.
+fn write_raw<W: Write>(
+    writer: &mut W,
+    last: bool,
+    data: &[u8],
+    target: usize,
+    stats: &mut EncoderStats,
+) -> Result<u64, IoError> {
+    let size = if target == 0 { data.len() } else { target - BLOCK_HEADER_SIZE }.max(1);
+    let count = data.len().div_ceil(size);
+    for (i, chunk) in data.chunks(size).enumerate() {
+        write_block_header(writer, last && i + 1 == count, BlockType::Raw, chunk.len())?;
+        writer.write_all(chunk)?;
+        stats.add_block(BlockType::Raw, chunk.len(), chunk.len());
+    }
+    Ok(count as u64)
+}
+
+/// Compress as much of `input` as fits into `output` as one complete frame,
+/// for packing records into fixed-size pages.  Returns how much of `input`
+/// the frame holds, and its size, which is never more than `output.len()`.
+/// The last block is cut short where the space runs out.  Fails with
+/// [`WriteZero`](std::io::ErrorKind::WriteZero) if `output` can't even hold
+/// an empty frame.
+pub fn compress_to_fit(
+    input: &[u8],
+    output: &mut [u8],
+    params: &CompressionParams,
+) -> Result<(usize, usize), IoError> {
+    // The frame header records the size of the part that fits, which takes
+    // no more room than the size of all of the input would.
+    let params = params.adjust(input.len() as u64);
+    let mut header = Vec::new();
+    write_header(&mut header, &params, Some(input.len() as u64), None)?;
+    let checksum = if params.checksum { 4 } else { 0 };
+    let budget = match output.len().checked_sub(header.len() + checksum) {
+        Some(budget) if budget >= BLOCK_HEADER_SIZE => budget,
+        _ => return Err(IoError::new(ErrorKind::WriteZero, "output too small for a frame")),
+    };
+
+    let mut blocks = Vec::with_capacity(budget + checksum);
+    let frame = FrameEncoder::resume(&params, &[], BlockEncoder::new(), params.checksum);
+    let consumed = frame.finish_to_fit(&mut blocks, input, budget)?;
+    let mut writer = &mut output[..];
+    write_header(&mut writer, &params, Some(consumed as u64), None)?;
+    writer.write_all(&blocks)?;
+    let remaining = writer.len();
+    Ok((consumed, output.len() - remaining))
+}
+
+/// Amount of history to drop at a time, a multiple of the match finder's
+/// table sizes.
+fn slide_step(params: &CompressionParams) -> usize {
+    (1usize << params.chain_log).max(1 << params.window_log.min(30))
+}
+
+/// Whether the bytes of a block, sampled, are spread so evenly that
+/// Huffman coding can't save anything.
+fn looks_random(block: &[u8]) -> bool {
+    let mut counts = [0u32; 256];
+    let samples = block.iter().step_by(SAMPLE_STEP);
+    let total = samples.len() as f64;
+    samples.for_each(|&byte| counts[usize::from(byte)] += 1);
+    let bits: f64 = counts
+        .iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| f64::from(count) * (total / f64::from(count)).log2())
+        .sum();
+    bits > RANDOM_BITS * total
+}
+
+/// A frame being compressed, on this thread or by workers.
+enum ActiveFrame {
+    Serial(Box<FrameEncoder>),
+    Parallel(Box<ParallelEncoder>),
+}
+
+impl ActiveFrame {
+    fn write<W: Write>(&mut self, writer: &mut W, buf: &[u8]) -> Result<(), IoError> {
+        match self {
+            ActiveFrame::Serial(frame) => frame.write(writer, buf),
+            ActiveFrame::Parallel(frame) => frame.write(writer, buf),
+        }
+    }
+
+    fn flush<W: Write>(&mut self, writer: &mut W) -> Result<(), IoError> {
+        match self {
+            ActiveFrame::Serial(frame) => frame.compress_pending(writer, false),
+            ActiveFrame::Parallel(frame) => frame.flush(writer),
+        }
+    }
+
+    fn stats(&self) -> EncoderStats {
+        match self {
+            ActiveFrame::Serial(frame) => frame.stats,
+            ActiveFrame::Parallel(frame) => frame.stats,
+        }
+    }
+
+    fn finish<W: Write>(self, writer: &mut W) -> Result<EncoderStats, IoError> {
+        match self {
+            ActiveFrame::Serial(frame) => frame.finish(writer),
+            ActiveFrame::Parallel(frame) => frame.finish(writer),
+        }
+    }
+}
+
+/// A writer that counts the bytes written to the one it wraps.
+pub(crate) struct Counted<W> {
+    pub(crate) inner: W,
+    pub(crate) count: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
 
 /// A ZStandard Stream Encoder that implements [`Write`](std::io::Write)
-pub struct Encoder<W: Write>(W, Frame, u32);
+///
+/// Everything written goes into one frame, which ends when
+/// [`finish`](Self::finish) is called (or earlier, with
+/// [`rotate_size`](Self::rotate_size) or
+/// [`rotate_interval`](Self::rotate_interval)).  Dropping the encoder without
+/// finishing loses the end of the frame; see
+/// [`auto_finish`](Self::auto_finish).
+pub struct Encoder<W: Write> {
+    writer: Counted<W>,
+    params: CompressionParams,
+    dictionary: Option<Dictionary>,
+    /// The dictionary is a reference prefix, which the window has to cover.
+    prefix: bool,
+    /// Input that fits in a block, in case it's all there is, so the frame
+    /// can record its size.  Anything longer is compressed as it comes.
+    buffer: Vec<u8>,
+    /// The frame, once its header is written.
+    frame: Option<ActiveFrame>,
+    /// The pledged size, if any.
+    pledged: Option<u64>,
+    /// Bytes written so far to the pledged frame.
+    written: u64,
+    /// The pledged frame has been written in full.
+    done: bool,
+    /// Worker threads, or 0 to compress on the calling thread.
+    workers: usize,
+    /// The bounds to adapt the level within.
+    adapt: Option<(i32, i32)>,
+    /// Output size and age at which a frame ends and another starts.
+    rotate_size: Option<u64>,
+    rotate_interval: Option<Duration>,
+    /// When the frame got its first input, and where its output starts.
+    frame_started: Option<Instant>,
+    frame_offset: u64,
+    /// Called with the range of the output each frame takes.
+    on_frame: Option<Box<dyn FnMut(Range<u64>)>>,
+    /// Counts for finished frames.
+    stats: EncoderStats,
+}
 
 impl<W: Write> Encoder<W> {
     /// Create a new ZStandard stream encoder that writes to a `Write`r.
     pub fn new(writer: W) -> Self {
-        Self(writer, Frame::default(), 100_000 /* .1 MB */)
+        Self {
+            writer: Counted { inner: writer, count: 0 },
+            params: CompressionParams::default(),
+            dictionary: None,
+            prefix: false,
+            buffer: Vec::new(),
+            frame: None,
+            pledged: None,
+            written: 0,
+            done: false,
+            workers: 0,
+            adapt: None,
+            rotate_size: None,
+            rotate_interval: None,
+            frame_started: None,
+            frame_offset: 0,
+            on_frame: None,
+            stats: EncoderStats::default(),
+        }
+    }
+
+    /// Set the compression level.  Levels 1 to 22 trade speed for ratio
+    /// the same way as the zstd command line tool, negative levels go
+    /// faster still, and 0 selects the default (3).  This replaces all
+    /// compression parameters, including the window size, but keeps the
+    /// frame flags.
+    pub fn level(mut self, level: i32) -> Self {
+        self.params = self.params.with_level(level);
+        self
+    }
+
+    /// Replace all parameters, for finer control than a level gives.
+    pub fn params(mut self, params: CompressionParams) -> Self {
+        self.params = params;
+        self
     }
-    
-    /// Override the window size.  The maximum that can be decoded by all ZStd
-    /// compliant decoders is 8_000_000 (8 MB).  The default is 100_000 (.1 MB).
+
+    /// Override the window size (rounded up to the next size a frame header
+    /// can describe).  The maximum that can be decoded by all ZStd
+    /// compliant decoders is 8_000_000 (8 MB).  The default depends on the
+    /// level (2 MiB for level 3).
     pub fn window_size(mut self, size: u32) -> Self {
-        self.2 = size;
+        self.params = self.params.window_size(size.into());
+        self
+    }
+
+    /// Append an XXH64 checksum of the content to each frame (on by
+    /// default).  Turning it off saves 4 bytes and the hashing per frame.
+    pub fn checksum(mut self, enabled: bool) -> Self {
+        self.params = self.params.checksum(enabled);
+        self
+    }
+
+    /// Enable long distance matching, which finds repeats far apart across
+    /// a window of at least 128 MiB.  Call [`window_size`](Self::window_size)
+    /// afterwards for a larger window (up to 2 GiB).  See
+    /// [`CompressionParams::long_distance`].
+    pub fn long_distance(mut self, enabled: bool) -> Self {
+        self.params = self.params.long_distance(enabled);
+        self
+    }
+
+    /// End jobs at content-defined points, so unchanged regions of the
+    /// input give the same compressed bytes.  See
+    /// [`CompressionParams::rsyncable`].
+    pub fn rsyncable(mut self, enabled: bool) -> Self {
+        self.params = self.params.rsyncable(enabled);
+        self
+    }
+
+    /// End blocks once their estimated compressed size reaches `size`, so
+    /// each can be sent on its own, such as in a network packet.  See
+    /// [`CompressionParams::target_block_size`].
+    pub fn target_block_size(mut self, size: usize) -> Self {
+        self.params = self.params.target_block_size(size);
         self
     }
+
+    /// Compress with a dictionary, which the decoder needs as well.  Every
+    /// frame starts from the dictionary's content, tables and repeat
+    /// offsets, and records its ID (unless
+    /// [`CompressionParams::dictionary_id`] turns that off).
+    pub fn with_dictionary(mut self, dictionary: &Dictionary) -> Self {
+        self.dictionary = Some(dictionary.clone());
+        self.prefix = false;
+        self
+    }
+
+    /// Compress against `prefix`, such as the old version of a file, so
+    /// the frame only holds what changed (like `zstd --patch-from`).  The
+    /// decoder needs the same prefix.  The window is raised to cover the
+    /// prefix and the content (taken to be the size of the prefix when it
+    /// isn't known ahead), with long distance matching if that's past the
+    /// level's own window.  This replaces any dictionary.
+    pub fn ref_prefix(mut self, prefix: &[u8]) -> Self {
+        self.dictionary = Some(Dictionary::raw(prefix));
+        self.prefix = true;
+        self
+    }
+
+    /// Promise to write exactly `size` bytes, so the frame header records
+    /// the size.  The frame is finished as soon as the last byte is
+    /// written.  Writing more than `size` bytes, or finishing before all of
+    /// them are written, fails with
+    /// [`InvalidInput`](std::io::ErrorKind::InvalidInput).
+    pub fn pledged_size(mut self, size: u64) -> Self {
+        self.pledged = Some(size);
+        self
+    }
+
+    /// Compress on `workers` threads (0, the default, compresses on the
+    /// calling thread), in jobs of [`CompressionParams::job_size`] that see
+    /// [`CompressionParams::overlap_log`] of the data before them.  The
+    /// output is the same for any number of workers from 1 up.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Adapt the level to how fast the writer takes the output, like
+    /// `zstd --adapt`: every few blocks (or jobs, with workers), the level
+    /// goes up one if more time went to writing than to compressing, and
+    /// down one if less, staying between `min` and `max`.  It starts from
+    /// the level set with [`level`](Self::level) or
+    /// [`params`](Self::params) (3 if neither), moved within the bounds,
+    /// and the window stays the one the frame started with.
+    pub fn adapt(mut self, min: i32, max: i32) -> Self {
+        self.adapt = Some((min, max));
+        self
+    }
+
+    /// End the frame and start another once it takes `bytes` of output,
+    /// so the output can be cut into files that decode on their own, as
+    /// for log shipping.  Frames end between blocks, so they run a little
+    /// over (by a few jobs, with workers).  This doesn't apply to a
+    /// pledged size.
+    pub fn rotate_size(mut self, bytes: u64) -> Self {
+        self.rotate_size = Some(bytes);
+        self
+    }
+
+    /// End the frame and start another once `interval` has passed since
+    /// it got its first input.  This is checked on every write and flush,
+    /// and doesn't apply to a pledged size.
+    pub fn rotate_interval(mut self, interval: Duration) -> Self {
+        self.rotate_interval = Some(interval);
+        self
+    }
+
+    /// Call `callback` with the range of the output each frame takes, once
+    /// the frame has ended.  It runs on the calling thread.
+    pub fn on_frame<F: FnMut(Range<u64>) + 'static>(mut self, callback: F) -> Self {
+        self.on_frame = Some(Box::new(callback));
+        self
+    }
+
+    /// End the frame with the last block and the checksum, then flush and
+    /// return the inner writer.
+    pub fn finish(mut self) -> Result<W, IoError> {
+        if self.pledged.is_some_and(|size| self.written != size) {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "fewer bytes written than the pledged size",
+            ));
+        }
+        // There's no frame left to end if the input so far all went into
+        // earlier ones.
+        let ended = self.done || self.writer.count > 0;
+        if self.frame.is_some() || !self.buffer.is_empty() || !ended {
+            self.end_frame()?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer.inner)
+    }
+
+    /// Counts of the blocks written so far.  Input held back for the next
+    /// block isn't counted until a flush.
+    pub fn stats(&self) -> EncoderStats {
+        let mut stats = self.stats;
+        if let Some(frame) = &self.frame {
+            stats.add(&frame.stats());
+        }
+        stats
+    }
+
+    /// Wrap the encoder so the frame is finished when it's dropped.  Errors
+    /// while finishing are ignored; call [`finish`](Self::finish) to see
+    /// them.
+    pub fn auto_finish(self) -> AutoFinishEncoder<W> {
+        AutoFinishEncoder(Some(self))
+    }
+
+    /// Parameters for a frame of `content_size` bytes, with the window
+    /// raised to reach back over a reference prefix.
+    fn frame_params(&self, content_size: Option<u64>) -> CompressionParams {
+        let prefix = match &self.dictionary {
+            Some(prefix) if self.prefix => prefix.content().len() as u64,
+            _ => return self.params,
+        };
+        let size = prefix + content_size.unwrap_or(prefix);
+        if size <= self.params.window() as u64 {
+            return self.params;
+        }
+        self.params.long_distance(true).window_size(size)
+    }
+
+    /// The level adapting for a new frame, if it does, with `params` moved
+    /// to the level it starts at.
+    fn start_adapt(&self, params: &mut CompressionParams) -> Option<Adapt> {
+        let (min, max) = self.adapt?;
+        let adapt = Adapt::new(params.level, min, max);
+        if adapt.level() != params.level {
+            *params = params.at_level(adapt.level());
+        }
+        Some(adapt)
+    }
+
+    /// End the frame, which can be just buffered input (or nothing), and
+    /// report it.
+    fn end_frame(&mut self) -> Result<(), IoError> {
+        if let Some(frame) = self.frame.take() {
+            let stats = frame.finish(&mut self.writer)?;
+            self.stats.add(&stats);
+        } else {
+            // Everything fit in the buffer (or nothing was written), so the
+            // frame can record its size.
+            let size = self.buffer.len() as u64;
+            let mut params = self.frame_params(Some(size));
+            let adapt = self.start_adapt(&mut params);
+            let dictionary = self.dictionary.as_ref();
+            let mut frame = FrameEncoder::new(&mut self.writer, &params, Some(size), dictionary)?;
+            frame.adapt = adapt;
+            frame.write(&mut self.writer, &self.buffer)?;
+            self.stats.add(&frame.finish(&mut self.writer)?);
+            self.buffer.clear();
+        }
+        self.frame_ended();
+        Ok(())
+    }
+
+    /// Report the frame that just ended, and start counting the next one.
+    fn frame_ended(&mut self) {
+        let end = self.writer.count;
+        if let Some(callback) = &mut self.on_frame {
+            callback(self.frame_offset..end);
+        }
+        self.frame_offset = end;
+        self.frame_started = None;
+    }
+
+    /// End the frame if it's due to rotate.
+    fn rotate(&mut self) -> Result<(), IoError> {
+        if self.pledged.is_some() || (self.frame.is_none() && self.buffer.is_empty()) {
+            return Ok(());
+        }
+        let size = self.writer.count - self.frame_offset;
+        let full = self.rotate_size.is_some_and(|bytes| size >= bytes);
+        let age = self.frame_started.map_or(Duration::ZERO, |started| started.elapsed());
+        let old = self.rotate_interval.is_some_and(|interval| age >= interval);
+        if full || old {
+            self.end_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Start the frame if it hasn't been yet, with any buffered input.
+    fn start_frame(&mut self) -> Result<(), IoError> {
+        if self.frame.is_none() {
+            let params = &mut self.frame_params(self.pledged);
+            let adapt = self.start_adapt(params);
+            let writer = &mut self.writer;
+            let dictionary = self.dictionary.as_ref();
+            let workers = if self.params.rsyncable { self.workers.max(1) } else { self.workers };
+            let mut frame = match workers {
+                0 => ActiveFrame::Serial(Box::new(FrameEncoder::new(
+                    writer,
+                    params,
+                    self.pledged,
+                    dictionary,
+                )?)),
+                workers => ActiveFrame::Parallel(Box::new(ParallelEncoder::new(
+                    writer,
+                    params,
+                    self.pledged,
+                    dictionary,
+                    workers,
+                )?)),
+            };
+            match &mut frame {
+                ActiveFrame::Serial(frame) => frame.adapt = adapt,
+                ActiveFrame::Parallel(frame) => frame.adapt = adapt,
+            }
+            frame.write(writer, &self.buffer)?;
+            self.buffer = Vec::new();
+            self.frame = Some(frame);
+        }
+        Ok(())
+    }
 }
 
 impl<W: Write> Write for Encoder<W> {
+    /// Compress everything written so far into blocks, without ending the
+    /// frame (unless it's due to rotate), so a decoder can read all of it.
     fn flush(&mut self) -> Result<(), IoError> {
-        // Write the last (smaller) frame.
-        self.1.encode(&mut self.0)
-    }
-
-    fn write(&mut self, mut buf: &[u8]) -> Result<usize, IoError> {
-        // Entire length of the buffer.
-        let orig_len = buf.len();
-        // Make frames until there are no remaining bytes.
-        while buf.is_empty() {
-            // Attempt to fill up the frame 
-            for i in 0..self.2 as usize - self.1.data.len() {
-                if let Some(byte) = buf.get(i).cloned() {
-                    self.1.data.push(byte);
-                } else {
-                    return Ok(orig_len - buf.len());
-                }
+        if self.frame.is_some() || !self.buffer.is_empty() {
+            self.start_frame()?;
+            self.frame.as_mut().unwrap().flush(&mut self.writer)?;
+            self.rotate()?;
+        }
+        self.writer.flush()
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        if let Some(size) = self.pledged {
+            if buf.len() as u64 > size - self.written {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    "more bytes written than the pledged size",
+                ));
+            }
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            self.start_frame()?;
+            self.frame.as_mut().unwrap().write(&mut self.writer, buf)?;
+            self.written += buf.len() as u64;
+            if self.written == size {
+                self.end_frame()?;
+                self.done = true;
             }
-            // If the frame is filled, compress it.
-            self.1.encode(&mut self.0)?;
-            // Shrink readable buffer slice.
-            buf = &buf[self.2 as usize..];
+            return Ok(buf.len());
+        }
+
+        // Take a block at a time when rotating by size, so frames end close
+        // to it.
+        let buf = match self.rotate_size {
+            Some(_) => &buf[..buf.len().min(self.params.block_size())],
+            None => buf,
+        };
+        if self.frame_started.is_none() && !buf.is_empty() {
+            self.frame_started = Some(Instant::now());
+        }
+        if self.frame.is_none()
+            && self.workers == 0
+            && self.rotate_size.is_none()
+            && self.buffer.len() + buf.len() <= self.params.block_size()
+        {
+            self.buffer.extend_from_slice(buf);
+        } else {
+            self.start_frame()?;
+            let frame = self.frame.as_mut().unwrap();
+            frame.write(&mut self.writer, buf)?;
+            // Count the block held back for the end of the frame as well,
+            // at the cost of an empty last block.
+            if let (Some(_), ActiveFrame::Serial(frame)) = (self.rotate_size, frame) {
+                frame.compress_pending(&mut self.writer, false)?;
+            }
+        }
+        self.rotate()?;
+        Ok(buf.len())
+    }
+}
+
+/// An [`Encoder`] that finishes its frame when dropped, made by
+/// [`Encoder::auto_finish`].
+pub struct AutoFinishEncoder<W: Write>(Option<Encoder<W>>);
+
+impl<W: Write> Write for AutoFinishEncoder<W> {
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.0.as_mut().unwrap().flush()
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.0.as_mut().unwrap().write(buf)
+    }
+}
+
+impl<W: Write> Drop for AutoFinishEncoder<W> {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.0.take() {
+            let _ = encoder.finish();
         }
-        // Successfully wrote entire buffer.
-        Ok(orig_len)
     }
 }
@@ -0,0 +1,135 @@
+//! Fast match finder: a single hash table with one candidate per entry.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/compress/zstd_fast.c
+
+use super::matcher::{self, MatchFinder, HASH_READ_SIZE};
+use super::params::CompressionParams;
+use super::sequence::Sequence;
+
+/// How quickly to skip ahead through data without matches.
+const SEARCH_STRENGTH: u32 = 8;
+
+/// Finds matches by looking up the last position with the same hash.
+pub(crate) struct FastMatcher {
+    table: Vec<u32>,
+    hash_log: u8,
+    min_match: u8,
+    step: usize,
+    window: usize,
+    rep: [u32; 2],
+}
+
+impl FastMatcher {
+    /// Create a fast match finder.
+    pub(crate) fn new(params: &CompressionParams) -> Self {
+        Self {
+            table: vec![0; 1 << params.hash_log],
+            hash_log: params.hash_log,
+            min_match: params.min_match.clamp(4, 7),
+            step: params.target_length.max(1) as usize,
+            window: params.window(),
+            rep: [1, 4],
+        }
+    }
+
+    /// Check that the first `min_match` bytes at `a` and `b` are equal.
+    #[inline(always)]
+    fn equal(&self, data: &[u8], a: usize, b: usize) -> bool {
+        let diff = matcher::read_u64(data, a) ^ matcher::read_u64(data, b);
+        diff << (64 - 8 * u32::from(self.min_match)) == 0
+    }
+}
+
+impl MatchFinder for FastMatcher {
+    fn find(&mut self, data: &[u8], start: usize, end: usize, sequences: &mut Vec<Sequence>) {
+        let limit = end.saturating_sub(HASH_READ_SIZE);
+        let mut anchor = start;
+        let mut ip = start.max(1);
+
+        while ip < limit {
+            let hash = matcher::hash(data, ip, self.min_match, self.hash_log);
+            let candidate = self.table[hash] as usize;
+            self.table[hash] = ip as u32;
+
+            // Try the most recent offset one position ahead first.
+            let rep = self.rep[0] as usize;
+            let (mut pos, mut len, offset) = if rep <= ip + 1
+                && rep <= self.window
+                && matcher::read_u32(data, ip + 1 - rep) == matcher::read_u32(data, ip + 1)
+            {
+                let len = 4 + matcher::match_len(data, ip + 5 - rep, ip + 5, end);
+                (ip + 1, len, rep)
+            } else if candidate < ip
+                && ip - candidate <= self.window
+                && self.equal(data, candidate, ip)
+            {
+                let offset = ip - candidate;
+                let len = matcher::match_len(data, candidate, ip, end);
+                (ip, len, offset)
+            } else {
+                ip += ((ip - anchor) >> SEARCH_STRENGTH) + self.step;
+                continue;
+            };
+
+            // Extend the match backwards over the literals.
+            while pos > anchor && pos > offset && data[pos - 1] == data[pos - 1 - offset] {
+                pos -= 1;
+                len += 1;
+            }
+
+            sequences.push(Sequence {
+                lit_len: (pos - anchor) as u32,
+                match_len: len as u32,
+                offset: offset as u32,
+            });
+            if offset as u32 != self.rep[0] {
+                self.rep = [offset as u32, self.rep[0]];
+            }
+            ip = pos + len;
+            anchor = ip;
+
+            // Fill in some of the skipped positions.
+            for fill in [pos + 2, ip - 2].iter().cloned() {
+                if fill < limit && fill > pos {
+                    let hash = matcher::hash(data, fill, self.min_match, self.hash_log);
+                    self.table[hash] = fill as u32;
+                }
+            }
+        }
+    }
+
+    fn slide(&mut self, amount: usize) {
+        matcher::slide(&mut self.table, amount);
+    }
+
+    fn fill(&mut self, data: &[u8], start: usize, end: usize) {
+        for pos in start.max(1)..end.saturating_sub(HASH_READ_SIZE) {
+            let hash = matcher::hash(data, pos, self.min_match, self.hash_log);
+            self.table[hash] = pos as u32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FastMatcher, MatchFinder};
+    use crate::matcher::tests::execute;
+    use crate::params::CompressionParams;
+
+    #[test]
+    fn sequences_rebuild_input() {
+        let mut data = Vec::new();
+        for i in 0..20_000u32 {
+            data.extend_from_slice(format!("line {} of {}\n", i % 300, i % 7).as_bytes());
+        }
+        let mut matcher = FastMatcher::new(&CompressionParams::level(1));
+        let mut sequences = Vec::new();
+        let middle = data.len() / 2;
+        matcher.find(&data, 0, middle, &mut sequences);
+        assert_eq!(execute(&data, 0, middle, &sequences), &data[..middle]);
+        let mut more = Vec::new();
+        matcher.find(&data, middle, data.len(), &mut more);
+        assert_eq!(execute(&data, middle, data.len(), &more), data);
+        assert!(sequences.len() + more.len() > 1000);
+    }
+}
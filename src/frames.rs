@@ -0,0 +1,135 @@
+//! Finding the frames in a stream from their headers and block headers,
+//! without decoding them, so a file of many frames can be split up.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/decompress/zstd_decompress.c (ZSTD_findFrameSizeInfo)
+
+use std::io::{Cursor, Error as IoError, ErrorKind, Read, Seek, SeekFrom};
+
+use super::parser::LeDecoder;
+use super::{DecError, FrameHeader, FrameType, BLOCK_SIZE_MAX};
+
+/// Where a frame is in a stream, and how much it decompresses to at most.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// What the frame's header says.
+    pub header: FrameHeader,
+    /// Position of the frame's magic number in the stream.
+    pub offset: u64,
+    /// Size of the whole frame, from the magic number to the checksum.
+    pub compressed_size: u64,
+    /// The content size if the header records it.  Otherwise the sizes of
+    /// the raw and RLE blocks, plus the largest block size for each
+    /// compressed one.  0 for a skippable frame.
+    pub decompressed_bound: u64,
+}
+
+/// Find the frames in `data`.
+pub fn find_frames(data: &[u8]) -> Result<Vec<FrameInfo>, IoError> {
+    read_frames(&mut Cursor::new(data))
+}
+
+/// Find the frames from the current position of `reader` to its end,
+/// seeking over the blocks.  Offsets are from where it started.
+pub fn read_frames<R: Read + Seek>(reader: &mut R) -> Result<Vec<FrameInfo>, IoError> {
+    let start = reader.stream_position()?;
+    let end = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(start))?;
+    // Seeking past the end succeeds, so check against it instead.
+    let skip = |reader: &mut R, amount: u64| -> Result<(), IoError> {
+        let pos = reader.seek(SeekFrom::Current(amount as i64))?;
+        if pos > end {
+            return Err(IoError::from(ErrorKind::UnexpectedEof));
+        }
+        Ok(())
+    };
+
+    let mut frames = Vec::new();
+    let mut offset = start;
+    while let Some(header) = FrameHeader::read_next(reader)? {
+        let mut bound = 0;
+        match header.frame_type {
+            FrameType::Skippable(_) => skip(reader, header.content_size.unwrap())?,
+            FrameType::Zstandard => {
+                let block_max = header.window_size.min(BLOCK_SIZE_MAX as u64);
+                loop {
+                    let block_header = LeDecoder::new(&mut *reader).u24()?;
+                    let block_size = u64::from(block_header >> 3);
+                    // Bytes stored, and most bytes decoded.
+                    let (stored, decoded) = match block_header >> 1 & 0b11 {
+                        0 => (block_size, block_size),
+                        1 => (1, block_size),
+                        2 => (block_size, block_max),
+                        _ => Err(DecError::InvalidBlockType)?,
+                    };
+                    if block_size > block_max {
+                        Err(DecError::BlockSize)?
+                    }
+                    bound += decoded;
+                    skip(reader, stored)?;
+                    if block_header & 1 != 0 {
+                        break;
+                    }
+                }
+                if header.checksum {
+                    skip(reader, 4)?;
+                }
+                bound = header.content_size.unwrap_or(bound);
+            }
+        }
+        let next = reader.stream_position()?;
+        frames.push(FrameInfo {
+            header,
+            offset: offset - start,
+            compressed_size: next - offset,
+            decompressed_bound: bound,
+        });
+        offset = next;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_frames, read_frames};
+    use crate::{compress, decompress, Encoder, FrameType};
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    #[test]
+    fn frames() {
+        let text: Vec<u8> =
+            (0..50_000u32).flat_map(|i| format!("{};", i % 700).into_bytes()).collect();
+        let sized = compress(&text, 3);
+        let mut encoder = Encoder::new(Vec::new()).checksum(false);
+        encoder.write_all(&text).unwrap();
+        encoder.write_all(&text).unwrap();
+        let streamed = encoder.finish().unwrap();
+        let skippable = [0x50, 0x2A, 0x4D, 0x18, 2, 0, 0, 0, 9, 9];
+        let data = [&sized[..], &skippable, &streamed].concat();
+
+        let frames = find_frames(&data).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!((frames[0].offset, frames[0].compressed_size), (0, sized.len() as u64));
+        assert_eq!(frames[0].decompressed_bound, text.len() as u64);
+        assert!(frames[0].header.checksum);
+        assert_eq!(frames[1].header.frame_type, FrameType::Skippable(0));
+        assert_eq!(frames[1].compressed_size, 10);
+        assert_eq!(frames[1].decompressed_bound, 0);
+        assert_eq!(frames[2].offset, sized.len() as u64 + 10);
+        assert_eq!(frames[2].compressed_size, streamed.len() as u64);
+        assert!(!frames[2].header.checksum);
+        assert!(frames[2].decompressed_bound >= 2 * text.len() as u64);
+        let (start, end) = (frames[2].offset as usize, data.len());
+        assert_eq!(decompress(&data[start..end]).unwrap().len(), 2 * text.len());
+
+        // From the middle of a stream.
+        let mut reader = Cursor::new(&data);
+        reader.seek(SeekFrom::Start(frames[1].offset)).unwrap();
+        let rest = read_frames(&mut reader).unwrap();
+        assert_eq!(rest.len(), 2);
+        assert_eq!(rest[1].offset, 10);
+
+        // Cut short.
+        assert!(find_frames(&data[..data.len() - 1]).is_err());
+        assert!(find_frames(&data[..sized.len() + 9]).is_err());
+    }
+}
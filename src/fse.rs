@@ -0,0 +1,508 @@
+//! Finite State Entropy coding.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#fse
+
+use super::bitstream::{BitReader, BitWriter};
+use super::DecError;
+
+/// Smallest accuracy log that can be described in a table header.
+pub(crate) const MIN_LOG: u8 = 5;
+
+/// Predefined literal length distribution.
+pub(crate) const LL_DEFAULT: (&[i16], u8) = (
+    &[
+        4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2,
+        2, 3, 2, 1, 1, 1, 1, 1, -1, -1, -1, -1,
+    ],
+    6,
+);
+
+/// Predefined match length distribution.
+pub(crate) const ML_DEFAULT: (&[i16], u8) = (
+    &[
+        1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1,
+        -1, -1, -1, -1, -1, -1,
+    ],
+    6,
+);
+
+/// Predefined offset code distribution.
+pub(crate) const OF_DEFAULT: (&[i16], u8) = (
+    &[
+        1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        -1, -1, -1, -1, -1,
+    ],
+    5,
+);
+
+/// Position of the highest set bit.
+#[inline(always)]
+pub(crate) fn highbit(value: u32) -> u32 {
+    31 - value.leading_zeros()
+}
+
+/// Pick an accuracy log for `src_size` symbols, no larger than `max_log`.
+pub(crate) fn optimal_log(max_log: u8, src_size: usize, max_symbol: usize) -> u8 {
+    let src_size = src_size.max(2) as u32;
+    let max_bits_src = highbit(src_size - 1).saturating_sub(2) as u8;
+    let min_bits = (highbit(src_size) + 1).min(highbit(max_symbol as u32 + 1) + 2) as u8;
+    let mut log = max_log.min(max_bits_src);
+    log = log.max(min_bits).max(MIN_LOG);
+    log.min(max_log)
+}
+
+/// Scale symbol counts so they sum up to `1 << log`.  Every symbol that
+/// appears keeps a probability of at least one cell; rare symbols get the
+/// special "less than one" probability (-1).
+pub(crate) fn normalize(counts: &[u32], log: u8) -> Vec<i16> {
+    let total: u64 = counts.iter().map(|&c| u64::from(c)).sum();
+    let table_size = 1i32 << log;
+    let low_threshold = total >> log;
+    let mut norm = vec![0i16; counts.len()];
+    let mut remaining = table_size;
+    for (s, &count) in counts.iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        let count = u64::from(count);
+        if count <= low_threshold {
+            norm[s] = -1;
+            remaining -= 1;
+        } else {
+            let proba = ((count << log) / total).max(1) as i32;
+            norm[s] = proba as i16;
+            remaining -= proba;
+        }
+    }
+    let largest = (0..counts.len())
+        .filter(|&s| norm[s] > 0)
+        .max_by_key(|&s| counts[s])
+        .unwrap();
+    // Hand out or take back the rounding error, starting with the most
+    // probable symbols, which lose the least accuracy per cell.
+    while remaining != 0 {
+        if remaining > 0 || norm[largest] > (-remaining) as i16 * 2 {
+            norm[largest] += remaining as i16;
+            break;
+        }
+        let (s, _) = norm
+            .iter()
+            .enumerate()
+            .filter(|(_, &n)| n > 1)
+            .max_by_key(|(_, &n)| n)
+            .unwrap();
+        norm[s] -= 1;
+        remaining += 1;
+    }
+    norm
+}
+
+/// Estimated cost in bits of encoding symbols with `counts` using a table
+/// built from `norm`, or `None` if some symbol can't be represented.
+pub(crate) fn cost(norm: &[i16], log: u8, counts: &[u32]) -> Option<usize> {
+    let mut bits = 0.0;
+    for (s, &count) in counts.iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        let n = match norm.get(s) {
+            Some(&n) if n != 0 => f64::from(n.max(1)),
+            _ => return None,
+        };
+        bits += f64::from(count) * (f64::from(log) - n.log2());
+    }
+    Some(bits as usize)
+}
+
+/// Write a table description (the normalized counts) for the decoder.
+pub(crate) fn write_header(norm: &[i16], log: u8, out: &mut Vec<u8>) {
+    let mut bits = BitPacker::default();
+    let table_size = 1i32 << log;
+    let mut remaining = table_size + 1;
+    let mut threshold = table_size;
+    let mut nb_bits = u32::from(log) + 1;
+    let mut symbol = 0;
+    let mut previous_zero = false;
+
+    bits.add(u32::from(log - MIN_LOG), 4, out);
+    while symbol < norm.len() && remaining > 1 {
+        if previous_zero {
+            let mut start = symbol;
+            while norm[symbol] == 0 {
+                symbol += 1;
+            }
+            while symbol >= start + 3 {
+                start += 3;
+                bits.add(3, 2, out);
+            }
+            bits.add((symbol - start) as u32, 2, out);
+        }
+        let mut count = i32::from(norm[symbol]);
+        symbol += 1;
+        let max = (2 * threshold - 1) - remaining;
+        remaining -= count.abs();
+        count += 1;
+        if count >= threshold {
+            count += max;
+        }
+        bits.add(count as u32, nb_bits - (count < max) as u32, out);
+        previous_zero = count == 1;
+        while remaining < threshold {
+            nb_bits -= 1;
+            threshold >>= 1;
+        }
+    }
+    bits.flush(out);
+}
+
+/// Read a table description with up to `max_symbols` symbols and an
+/// accuracy log of at most `max_log`: (normalized counts, accuracy log,
+/// bytes read).
+pub(crate) fn read_header(
+    data: &[u8],
+    max_symbols: usize,
+    max_log: u8,
+) -> Result<(Vec<i16>, u8, usize), DecError> {
+    let mut bits = BitUnpacker { data, pos: 0 };
+    let log = bits.read(4) as u8 + MIN_LOG;
+    if log > max_log {
+        return Err(DecError::Table);
+    }
+    let table_size = 1i32 << log;
+    let mut remaining = table_size + 1;
+    let mut threshold = table_size;
+    let mut nb_bits = u32::from(log) + 1;
+    let mut norm = Vec::new();
+    let mut previous_zero = false;
+
+    while remaining > 1 {
+        if previous_zero {
+            loop {
+                let zeros = bits.read(2);
+                norm.extend((0..zeros).map(|_| 0));
+                if zeros != 3 {
+                    break;
+                }
+            }
+        }
+        let max = (2 * threshold - 1) - remaining;
+        let mut count = (bits.peek(nb_bits) & ((1 << (nb_bits - 1)) - 1)) as i32;
+        if count < max {
+            bits.pos += nb_bits as usize - 1;
+        } else {
+            count = bits.read(nb_bits) as i32;
+            if count >= threshold {
+                count -= max;
+            }
+        }
+        count -= 1;
+        remaining -= count.abs();
+        norm.push(count as i16);
+        previous_zero = count == 0;
+        while remaining < threshold && threshold > 1 {
+            nb_bits -= 1;
+            threshold >>= 1;
+        }
+        if norm.len() > max_symbols {
+            return Err(DecError::Table);
+        }
+    }
+    let used = bits.pos.div_ceil(8);
+    if remaining != 1 || used > data.len() {
+        return Err(DecError::Table);
+    }
+    Ok((norm, log, used))
+}
+
+/// Forward bit unpacking, used for table descriptions.  Bits past the end
+/// read as zeros.
+struct BitUnpacker<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitUnpacker<'_> {
+    fn peek(&self, bits: u32) -> u32 {
+        let mut value = 0u64;
+        for (i, &byte) in self.data.iter().skip(self.pos / 8).take(5).enumerate() {
+            value |= u64::from(byte) << (8 * i);
+        }
+        ((value >> (self.pos % 8)) & ((1 << bits) - 1)) as u32
+    }
+
+    fn read(&mut self, bits: u32) -> u32 {
+        let value = self.peek(bits);
+        self.pos += bits as usize;
+        value
+    }
+}
+
+/// Forward bit packing, used for table descriptions.
+#[derive(Default)]
+struct BitPacker {
+    container: u32,
+    bits: u32,
+}
+
+impl BitPacker {
+    fn add(&mut self, value: u32, bits: u32, out: &mut Vec<u8>) {
+        self.container |= value << self.bits;
+        self.bits += bits;
+        while self.bits >= 8 {
+            out.push(self.container as u8);
+            self.container >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn flush(self, out: &mut Vec<u8>) {
+        if self.bits != 0 {
+            out.push(self.container as u8);
+        }
+    }
+}
+
+/// Lay out the symbols in the decoding table, the same way decoders do.
+pub(crate) fn spread(norm: &[i16], log: u8) -> Vec<u8> {
+    let table_size = 1usize << log;
+    let mask = table_size - 1;
+    let step = (table_size >> 1) + (table_size >> 3) + 3;
+    let mut table = vec![0u8; table_size];
+    let mut high = table_size - 1;
+    for (s, &n) in norm.iter().enumerate() {
+        if n == -1 {
+            table[high] = s as u8;
+            high = high.wrapping_sub(1);
+        }
+    }
+    let mut position = 0;
+    for (s, &n) in norm.iter().enumerate() {
+        for _ in 0..n.max(0) {
+            table[position] = s as u8;
+            position = (position + step) & mask;
+            while position > high {
+                position = (position + step) & mask;
+            }
+        }
+    }
+    table
+}
+
+/// An FSE encoding table.
+pub(crate) struct FseTable {
+    log: u8,
+    /// Next state, indexed by symbol start plus sub-state.
+    states: Vec<u16>,
+    /// Per symbol: (delta to find state, delta to get number of bits).
+    symbols: Vec<(i32, u32)>,
+}
+
+impl FseTable {
+    /// Build an encoding table from normalized counts.  A table with an
+    /// accuracy log of zero encodes a single symbol without any bits.
+    pub(crate) fn new(norm: &[i16], log: u8) -> Self {
+        if log == 0 {
+            return Self {
+                log,
+                states: Vec::new(),
+                symbols: Vec::new(),
+            };
+        }
+        let table_size = 1u32 << log;
+        let mut cumul = Vec::with_capacity(norm.len() + 1);
+        let mut total = 0u32;
+        for &n in norm {
+            cumul.push(total);
+            total += if n == -1 { 1 } else { n.max(0) as u32 };
+        }
+        let mut states = vec![0u16; table_size as usize];
+        for (u, &s) in spread(norm, log).iter().enumerate() {
+            let s = usize::from(s);
+            states[cumul[s] as usize] = (table_size + u as u32) as u16;
+            cumul[s] += 1;
+        }
+        let mut symbols = Vec::with_capacity(norm.len());
+        let mut total = 0i32;
+        for &n in norm {
+            let entry = match n {
+                0 => (0, ((u32::from(log) + 1) << 16) - table_size),
+                -1 | 1 => {
+                    let entry = (total - 1, (u32::from(log) << 16) - table_size);
+                    total += 1;
+                    entry
+                }
+                _ => {
+                    let n = n as u32;
+                    let max_bits_out = u32::from(log) - highbit(n - 1);
+                    let min_state_plus = n << max_bits_out;
+                    let entry = (total - n as i32, (max_bits_out << 16) - min_state_plus);
+                    total += n as i32;
+                    entry
+                }
+            };
+            symbols.push(entry);
+        }
+        Self { log, states, symbols }
+    }
+
+    /// Start encoding with the last symbol, without writing any bits.
+    #[inline(always)]
+    pub(crate) fn start(&self, symbol: u8) -> FseState<'_> {
+        if self.log == 0 {
+            return FseState { table: self, state: 0 };
+        }
+        let (find, nb) = self.symbols[usize::from(symbol)];
+        let bits_out = (nb + (1 << 15)) >> 16;
+        let value = (bits_out << 16).wrapping_sub(nb);
+        let index = ((value >> bits_out) as i32 + find) as usize;
+        FseState {
+            table: self,
+            state: u32::from(self.states[index]),
+        }
+    }
+}
+
+/// The state of an FSE encoder.
+pub(crate) struct FseState<'a> {
+    table: &'a FseTable,
+    state: u32,
+}
+
+impl FseState<'_> {
+    /// Encode one symbol (in reverse order from decoding).
+    #[inline(always)]
+    pub(crate) fn encode(&mut self, writer: &mut BitWriter, symbol: u8) {
+        if self.table.log == 0 {
+            return;
+        }
+        let (find, nb) = self.table.symbols[usize::from(symbol)];
+        let bits_out = (self.state + nb) >> 16;
+        writer.add(self.state, bits_out);
+        let index = ((self.state >> bits_out) as i32 + find) as usize;
+        self.state = u32::from(self.table.states[index]);
+    }
+
+    /// Write the final state, which the decoder reads first.
+    pub(crate) fn flush(&self, writer: &mut BitWriter) {
+        writer.add(self.state, u32::from(self.table.log));
+    }
+}
+
+/// An FSE decoding table.
+#[derive(Clone)]
+pub(crate) struct FseDecoder {
+    log: u8,
+    /// Per state: (symbol, bits to read, base of the next state).
+    entries: Vec<(u8, u8, u16)>,
+}
+
+impl FseDecoder {
+    /// Build a decoding table from normalized counts.
+    pub(crate) fn new(norm: &[i16], log: u8) -> Self {
+        let table_size = 1u32 << log;
+        let mut next: Vec<u32> = norm.iter().map(|&n| n.max(1) as u32).collect();
+        let entries = spread(norm, log)
+            .into_iter()
+            .map(|s| {
+                let state = next[usize::from(s)];
+                next[usize::from(s)] += 1;
+                let bits = u32::from(log) - highbit(state);
+                (s, bits as u8, ((state << bits) - table_size) as u16)
+            })
+            .collect();
+        Self { log, entries }
+    }
+
+    /// A table that always decodes `symbol`, without reading any bits.
+    pub(crate) fn rle(symbol: u8) -> Self {
+        Self { log: 0, entries: vec![(symbol, 0, 0)] }
+    }
+
+    /// Read the initial state.
+    #[inline(always)]
+    pub(crate) fn start(&self, reader: &mut BitReader<'_>) -> usize {
+        reader.read(self.log.into()) as usize
+    }
+
+    /// The symbol for a state.
+    #[inline(always)]
+    pub(crate) fn symbol(&self, state: usize) -> u8 {
+        self.entries[state].0
+    }
+
+    /// Move on to the next state.
+    #[inline(always)]
+    pub(crate) fn update(&self, state: usize, reader: &mut BitReader<'_>) -> usize {
+        let (_, bits, base) = self.entries[state];
+        usize::from(base) + reader.read(bits.into()) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_counts_fill_table() {
+        let counts = [1000, 1, 1, 50, 0, 7, 300, 2];
+        for log in 5..=9 {
+            let norm = normalize(&counts, log);
+            let sum: i32 = norm.iter().map(|&n| i32::from(n).abs()).sum();
+            assert_eq!(sum, 1 << log);
+            assert_eq!(norm[4], 0);
+            assert!(norm.iter().zip(&counts).all(|(&n, &c)| (n != 0) == (c != 0)));
+        }
+    }
+
+    #[test]
+    fn header_of_predefined_offsets() {
+        // Every probability fits in the shortened field, so the header is
+        // exactly as long as the sum of the field widths.
+        let mut out = Vec::new();
+        write_header(OF_DEFAULT.0, OF_DEFAULT.1, &mut out);
+        assert_eq!(out[0] & 0xF, 0);
+        assert!(out.len() < 16);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let counts = [1000, 1, 1, 50, 0, 0, 0, 0, 7, 300, 2];
+        let norm = normalize(&counts, 7);
+        let mut out = Vec::new();
+        write_header(&norm, 7, &mut out);
+        out.push(0xAA);
+        assert_eq!(read_header(&out, 16, 9).unwrap(), (norm, 7, out.len() - 1));
+        assert!(read_header(&out, 16, 6).is_err());
+    }
+
+    #[test]
+    fn decode_encoded_symbols() {
+        let symbols = [0u8, 3, 0, 0, 9, 1, 0, 3, 3, 0, 2, 0, 0, 9, 0];
+        let mut counts = [0u32; 10];
+        for &s in &symbols {
+            counts[usize::from(s)] += 1;
+        }
+        let norm = normalize(&counts, 5);
+        let table = FseTable::new(&norm, 5);
+        let mut writer = BitWriter::new();
+        let mut state = table.start(symbols[symbols.len() - 1]);
+        for &s in symbols[..symbols.len() - 1].iter().rev() {
+            state.encode(&mut writer, s);
+        }
+        state.flush(&mut writer);
+        let mut out = Vec::new();
+        writer.finish(&mut out);
+
+        let decoder = FseDecoder::new(&norm, 5);
+        let mut reader = BitReader::new(&out).unwrap();
+        let mut state = decoder.start(&mut reader);
+        let mut decoded = vec![decoder.symbol(state)];
+        while decoded.len() < symbols.len() {
+            state = decoder.update(state, &mut reader);
+            decoded.push(decoder.symbol(state));
+        }
+        assert_eq!(decoded, symbols);
+        assert!(reader.finished());
+    }
+}
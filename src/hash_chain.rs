@@ -0,0 +1,298 @@
+//! Hash chain match finder with greedy and lazy match selection, which can
+//! also search a binary tree.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/compress/zstd_lazy.c
+
+use super::binary_tree::BinaryTree;
+use super::matcher::{self, MatchFinder, HASH_READ_SIZE};
+use super::params::CompressionParams;
+use super::sequence::Sequence;
+
+/// How quickly to skip ahead through data without matches.
+const SEARCH_STRENGTH: u32 = 8;
+
+/// Finds matches by walking a chain of previous positions with the same
+/// hash, then optionally checks whether starting a match one or two bytes
+/// later pays off.
+pub(crate) struct HashChainMatcher {
+    hash_table: Vec<u32>,
+    chain_table: Vec<u32>,
+    hash_log: u8,
+    chain_mask: usize,
+    attempts: usize,
+    min_match: u8,
+    /// 0 for greedy, 1 for lazy, 2 for lazy2.
+    depth: u8,
+    window: usize,
+    next_to_update: usize,
+    rep: [u32; 2],
+    /// Searched instead of the chains, for btlazy2.
+    tree: Option<BinaryTree>,
+    /// Matches found in the tree at one position.
+    matches: Vec<(usize, usize)>,
+}
+
+impl HashChainMatcher {
+    /// Create a hash chain match finder that looks `depth` (0 to 2)
+    /// positions ahead for better matches.
+    pub(crate) fn new(params: &CompressionParams, depth: u8) -> Self {
+        Self {
+            hash_table: vec![0; 1 << params.hash_log],
+            chain_table: vec![0; 1 << params.chain_log],
+            hash_log: params.hash_log,
+            chain_mask: (1 << params.chain_log) - 1,
+            attempts: 1 << params.search_log,
+            min_match: params.min_match.clamp(4, 6),
+            depth,
+            window: params.window(),
+            next_to_update: 1,
+            rep: [1, 4],
+            tree: None,
+            matches: Vec::new(),
+        }
+    }
+
+    /// Create a match finder that looks two positions ahead like lazy2, but
+    /// finds matches in a binary tree rather than the hash chains.
+    pub(crate) fn binary_tree(params: &CompressionParams) -> Self {
+        Self {
+            hash_table: Vec::new(),
+            chain_table: Vec::new(),
+            tree: Some(BinaryTree::new(params)),
+            ..Self::new(params, 2)
+        }
+    }
+
+    /// Add all positions before `target` to the chains.
+    #[inline(always)]
+    fn insert(&mut self, data: &[u8], target: usize) {
+        while self.next_to_update < target {
+            let pos = self.next_to_update;
+            let hash = matcher::hash(data, pos, self.min_match, self.hash_log);
+            self.chain_table[pos & self.chain_mask] = self.hash_table[hash];
+            self.hash_table[hash] = pos as u32;
+            self.next_to_update += 1;
+        }
+    }
+
+    /// Find the longest match at `ip`: (length, offset).
+    fn search(&mut self, data: &[u8], ip: usize, end: usize) -> (usize, usize) {
+        if let Some(tree) = self.tree.as_mut() {
+            self.matches.clear();
+            tree.find(data, ip, end, usize::from(self.min_match) - 1, &mut self.matches);
+            return self.matches.last().copied().unwrap_or((0, 0));
+        }
+        self.insert(data, ip);
+        let lowest = ip
+            .saturating_sub(self.window)
+            .max(ip.saturating_sub(self.chain_mask))
+            .max(1);
+        let hash = matcher::hash(data, ip, self.min_match, self.hash_log);
+        let mut candidate = self.hash_table[hash] as usize;
+        let (mut best_len, mut best_offset) = (usize::from(self.min_match) - 1, 0);
+        for _ in 0..self.attempts {
+            if candidate < lowest {
+                break;
+            }
+            if data[candidate + best_len] == data[ip + best_len] {
+                let len = matcher::match_len(data, candidate, ip, end);
+                if len > best_len {
+                    best_len = len;
+                    best_offset = ip - candidate;
+                    if ip + len == end {
+                        break;
+                    }
+                }
+            }
+            let next = self.chain_table[candidate & self.chain_mask] as usize;
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+        if best_offset == 0 {
+            (0, 0)
+        } else {
+            (best_len, best_offset)
+        }
+    }
+
+    /// Rough cost in bits of coding `offset`; repeating the last offset is
+    /// nearly free.
+    #[inline(always)]
+    fn offset_cost(&self, offset: usize) -> i64 {
+        if offset == self.rep[0] as usize {
+            0
+        } else {
+            i64::from(31 - (offset as u32 + 3).leading_zeros())
+        }
+    }
+
+    /// Length of a match with the most recent offset at `ip`, if any.
+    #[inline(always)]
+    fn repeat(&self, data: &[u8], ip: usize, end: usize) -> usize {
+        let rep = self.rep[0] as usize;
+        if rep <= ip
+            && rep <= self.window
+            && matcher::read_u32(data, ip - rep) == matcher::read_u32(data, ip)
+        {
+            4 + matcher::match_len(data, ip + 4 - rep, ip + 4, end)
+        } else {
+            0
+        }
+    }
+}
+
+impl MatchFinder for HashChainMatcher {
+    fn find(&mut self, data: &[u8], start: usize, end: usize, sequences: &mut Vec<Sequence>) {
+        let limit = end.saturating_sub(HASH_READ_SIZE);
+        let mut anchor = start;
+        let mut ip = start.max(1);
+
+        while ip < limit {
+            // Best match starting at or right after `ip`.
+            let (mut pos, mut len, mut offset) = (ip, 0, 0);
+            let rep_len = self.repeat(data, ip + 1, end);
+            if rep_len != 0 {
+                pos = ip + 1;
+                len = rep_len;
+                offset = self.rep[0] as usize;
+            }
+            if self.depth != 0 || len == 0 {
+                let (found_len, found_offset) = self.search(data, ip, end);
+                if found_len > len {
+                    pos = ip;
+                    len = found_len;
+                    offset = found_offset;
+                }
+            }
+            if len == 0 {
+                ip += ((ip - anchor) >> SEARCH_STRENGTH) + 1;
+                continue;
+            }
+
+            // Look ahead for a better match, weighing length against the cost
+            // of the offset.
+            let mut ahead = 1;
+            while ahead <= self.depth && pos + usize::from(ahead) < limit {
+                let next = pos + usize::from(ahead);
+                let (rep_scale, bonus) = if ahead == 1 { (3, 4) } else { (4, 7) };
+
+                let rep_len = self.repeat(data, next, end) as i64;
+                let gain = len as i64 * rep_scale - self.offset_cost(offset) + 1;
+                if rep_len != 0 && rep_len * rep_scale > gain {
+                    pos = next;
+                    len = rep_len as usize;
+                    offset = self.rep[0] as usize;
+                    ahead = 1;
+                    continue;
+                }
+                let (found_len, found_offset) = self.search(data, next, end);
+                let found_gain = found_len as i64 * 4 - self.offset_cost(found_offset);
+                let gain = len as i64 * 4 - self.offset_cost(offset) + bonus;
+                if found_len != 0 && found_gain > gain {
+                    pos = next;
+                    len = found_len;
+                    offset = found_offset;
+                    ahead = 1;
+                    continue;
+                }
+                ahead += 1;
+            }
+
+            // Extend the match backwards over the literals.
+            while pos > anchor && pos > offset && data[pos - 1] == data[pos - 1 - offset] {
+                pos -= 1;
+                len += 1;
+            }
+
+            sequences.push(Sequence {
+                lit_len: (pos - anchor) as u32,
+                match_len: len as u32,
+                offset: offset as u32,
+            });
+            if offset as u32 != self.rep[0] {
+                self.rep = [offset as u32, self.rep[0]];
+            }
+            ip = pos + len;
+            anchor = ip;
+
+            // Matches with the second most recent offset need no literals.
+            while ip < limit {
+                let rep = self.rep[1] as usize;
+                if rep > ip
+                    || rep > self.window
+                    || matcher::read_u32(data, ip - rep) != matcher::read_u32(data, ip)
+                {
+                    break;
+                }
+                let len = 4 + matcher::match_len(data, ip + 4 - rep, ip + 4, end);
+                sequences.push(Sequence {
+                    lit_len: 0,
+                    match_len: len as u32,
+                    offset: rep as u32,
+                });
+                self.rep.swap(0, 1);
+                ip += len;
+                anchor = ip;
+            }
+        }
+    }
+
+    fn slide(&mut self, amount: usize) {
+        matcher::slide(&mut self.hash_table, amount);
+        matcher::slide(&mut self.chain_table, amount);
+        self.next_to_update = self.next_to_update.saturating_sub(amount).max(1);
+        if let Some(tree) = self.tree.as_mut() {
+            tree.slide(amount);
+        }
+    }
+
+    fn skip(&mut self, _data: &[u8], _start: usize, end: usize) {
+        self.next_to_update = self.next_to_update.max(end);
+        if let Some(tree) = self.tree.as_mut() {
+            tree.skip(end);
+        }
+    }
+
+    fn fill(&mut self, data: &[u8], start: usize, end: usize) {
+        self.skip(data, 0, start);
+        match self.tree.as_mut() {
+            Some(tree) => tree.fill(data, end),
+            None => self.insert(data, end.saturating_sub(HASH_READ_SIZE)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HashChainMatcher, MatchFinder};
+    use crate::matcher::tests::execute;
+    use crate::params::CompressionParams;
+
+    #[test]
+    fn sequences_rebuild_input() {
+        let mut data = Vec::new();
+        for i in 0..20_000u32 {
+            data.extend_from_slice(format!("{} {} {};", i % 251, i % 17, i % 1000).as_bytes());
+        }
+        let middle = data.len() / 3;
+        let mut sizes = Vec::new();
+        let params = CompressionParams::level(8);
+        let matchers = (0..=2)
+            .map(|depth| HashChainMatcher::new(&params, depth))
+            .chain(Some(HashChainMatcher::binary_tree(&params)));
+        for mut matcher in matchers {
+            let mut sequences = Vec::new();
+            matcher.find(&data, 0, middle, &mut sequences);
+            assert_eq!(execute(&data, 0, middle, &sequences), &data[..middle]);
+            let mut more = Vec::new();
+            matcher.find(&data, middle, data.len(), &mut more);
+            assert_eq!(execute(&data, middle, data.len(), &more), data);
+            sizes.push(sequences.len() + more.len());
+        }
+        // Looking ahead finds longer (so fewer) matches.
+        assert!(sizes[2] <= sizes[0]);
+        assert!(sizes[3] <= sizes[0]);
+    }
+}
@@ -0,0 +1,188 @@
+//! Frame headers, which can be looked at without decoding the frame.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#frame_header
+
+use std::io::{Error as IoError, Read};
+
+use super::parser::LeDecoder;
+use super::{DecError, MAGIC_NUMBER};
+
+/// Magic number of skippable frames, give or take the low 4 bits.
+const SKIPPABLE_MAGIC: u32 = 0x184D2A50;
+
+/// The kind of a frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameType {
+    /// A frame of compressed data.
+    Zstandard,
+    /// A frame of user data for decoders to skip, with the low 4 bits of
+    /// its magic number.
+    Skippable(u8),
+}
+
+/// What the header of a frame says about it.
+///
+/// For a skippable frame, the content size is the amount of user data
+/// after the header, and the rest is empty.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// Whether the frame holds compressed data, or is to be skipped.
+    pub frame_type: FrameType,
+    /// Size of the decompressed content, if the header records it.
+    pub content_size: Option<u64>,
+    /// Amount of history the decoder needs to keep.
+    pub window_size: u64,
+    /// ID of the dictionary the frame needs, if it records one.
+    pub dictionary_id: Option<u32>,
+    /// Whether the frame ends with a checksum of its content.
+    pub checksum: bool,
+    /// Whether the frame is one segment, so the window is its whole content.
+    pub single_segment: bool,
+}
+
+impl FrameHeader {
+    /// Parse the header at the start of `data`.  Returns it, and its length
+    /// (magic number included).
+    pub fn parse(data: &[u8]) -> Result<(FrameHeader, usize), IoError> {
+        let mut reader = data;
+        let header = Self::read(&mut reader)?;
+        Ok((header, data.len() - reader.len()))
+    }
+
+    /// Read a header, leaving `reader` just after it.
+    pub fn read<R: Read>(reader: &mut R) -> Result<FrameHeader, IoError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        Self::read_after(u32::from_le_bytes(magic), reader)
+    }
+
+    /// Read the next header, or `None` at the end of the input (running out
+    /// partway through is an error).
+    pub(crate) fn read_next<R: Read>(reader: &mut R) -> Result<Option<Self>, IoError> {
+        let mut magic = [0; 4];
+        if reader.read(&mut magic[..1])? == 0 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut magic[1..])?;
+        Self::read_after(u32::from_le_bytes(magic), reader).map(Some)
+    }
+
+    /// Read the rest of a header that starts with `magic`.
+    fn read_after<R: Read>(magic: u32, reader: &mut R) -> Result<Self, IoError> {
+        let mut dec = LeDecoder::new(reader);
+        if magic & !0xF == SKIPPABLE_MAGIC {
+            return Ok(Self {
+                frame_type: FrameType::Skippable((magic & 0xF) as u8),
+                content_size: Some(dec.u32()?.into()),
+                window_size: 0,
+                dictionary_id: None,
+                checksum: false,
+                single_segment: false,
+            });
+        }
+        if magic != MAGIC_NUMBER {
+            Err(DecError::MagicNumber)?
+        }
+
+        ///////////////////// Frame_Header ////////////////////
+
+        // Decode the frame header descriptor.
+        let frame_head_desc = dec.u8()?;
+        let frame_content_size_flag = frame_head_desc >> 6;
+        let single_segment = frame_head_desc & 0b0010_0000 != 0;
+        let unused_reserved_bits = frame_head_desc & 0b0001_1000;
+        let content_checksum = frame_head_desc & 0b0000_0100 != 0;
+        let dictionary_id_flag = frame_head_desc & 0b0000_0011;
+        if unused_reserved_bits != 0 {
+            Err(DecError::FrameHeaderDesc)?
+        }
+
+        // Window descriptor, unless the window is the whole frame.
+        let window_size: Option<u64> = if !single_segment {
+            let window_descriptor: u64 = dec.u8()?.into();
+            let exponent = window_descriptor >> 3;
+            let mantissa = window_descriptor & 0b0000_0111;
+            let window_base = 1 << (10 + exponent);
+            Some(window_base + window_base / 8 * mantissa)
+        } else {
+            None
+        };
+
+        // Dictionary ID field.
+        let dictionary_id: u32 = match dictionary_id_flag {
+            0 => 0,
+            1 => dec.u8()?.into(),
+            2 => dec.u16()?.into(),
+            _ => dec.u32()?,
+        };
+
+        // Frame content size.
+        let content_size: Option<u64> = match (frame_content_size_flag, single_segment) {
+            (0, false) => None,
+            (0, true) => Some(dec.u8()?.into()),
+            (1, _) => Some(u64::from(dec.u16()?) + 256),
+            (2, _) => Some(dec.u32()?.into()),
+            _ => Some(dec.u64()?),
+        };
+
+        Ok(Self {
+            frame_type: FrameType::Zstandard,
+            content_size,
+            window_size: window_size.or(content_size).unwrap(),
+            dictionary_id: Some(dictionary_id).filter(|&id| id != 0),
+            checksum: content_checksum,
+            single_segment,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameHeader, FrameType};
+    use crate::{compress, decompress, Encoder};
+    use std::io::Write;
+
+    #[test]
+    fn parse() {
+        let frame = compress(&[b'a'; 1000], 3);
+        let (header, len) = FrameHeader::parse(&frame).unwrap();
+        assert_eq!(len, 7);
+        assert_eq!(header.frame_type, FrameType::Zstandard);
+        assert_eq!(header.content_size, Some(1000));
+        assert_eq!(header.window_size, 1000);
+        assert!(header.single_segment && header.checksum);
+        assert_eq!(header.dictionary_id, None);
+        assert!(FrameHeader::parse(&frame[..len - 1]).is_err());
+        assert!(FrameHeader::parse(&frame[1..]).is_err());
+
+        // Streamed, so with no content size, and no checksum.
+        let mut encoder = Encoder::new(Vec::new()).checksum(false);
+        encoder.write_all(&[b'a'; 1 << 22]).unwrap();
+        let frame = encoder.finish().unwrap();
+        let header = FrameHeader::read(&mut &frame[..]).unwrap();
+        assert_eq!(header.content_size, None);
+        assert_eq!(header.window_size, 2 << 20);
+        assert!(!header.single_segment && !header.checksum);
+
+        // A 2 byte dictionary ID and a 2 MiB window.
+        let frame = [0x28, 0xB5, 0x2F, 0xFD, 0b10, 11 << 3, 0x34, 0x12];
+        let (header, len) = FrameHeader::parse(&frame).unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(header.dictionary_id, Some(0x1234));
+        assert_eq!(header.window_size, 2 << 20);
+    }
+
+    #[test]
+    fn skippable() {
+        let frame = [0x5A, 0x2A, 0x4D, 0x18, 3, 0, 0, 0, 1, 2, 3];
+        let (header, len) = FrameHeader::parse(&frame).unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(header.frame_type, FrameType::Skippable(0xA));
+        assert_eq!(header.content_size, Some(3));
+
+        // Decoders pass over them.
+        let frames = [&compress(b"abc", 1)[..], &frame, &compress(b"def", 1)].concat();
+        assert_eq!(decompress(&frames).unwrap(), b"abcdef");
+        assert!(decompress(&frame[..10]).is_err());
+    }
+}
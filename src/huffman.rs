@@ -0,0 +1,443 @@
+//! Huffman coding of literals.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#huffman-coding
+
+use super::bitstream::{BitReader, BitWriter};
+use super::fse::{self, FseDecoder, FseTable};
+use super::DecError;
+
+/// Longest code allowed by the format.
+pub(crate) const MAX_LOG: u8 = 11;
+/// Most accurate table allowed for FSE-compressed weights.
+const WEIGHTS_MAX_LOG: u8 = 6;
+
+/// A Huffman encoding table for literals.
+#[derive(Clone)]
+pub(crate) struct HuffmanTable {
+    codes: [u16; 256],
+    bits: [u8; 256],
+    max_bits: u8,
+    /// Highest symbol with a code.
+    last: usize,
+}
+
+impl HuffmanTable {
+    /// Build a table for the given symbol counts.  Returns `None` if fewer
+    /// than two symbols are used (which Huffman coding can't represent).
+    pub(crate) fn new(counts: &[u32; 256]) -> Option<Self> {
+        let bits = lengths(counts, MAX_LOG)?;
+        let max_bits = *bits.iter().max().unwrap();
+        let last = (0..256).rev().find(|&s| bits[s] != 0).unwrap();
+        let mut table = Self {
+            codes: [0; 256],
+            bits,
+            max_bits,
+            last,
+        };
+        table.assign_codes();
+        Some(table)
+    }
+
+    /// Read a table description, such as a dictionary's: (table, bytes
+    /// read).
+    pub(crate) fn read(data: &[u8]) -> Result<(Self, usize), DecError> {
+        let (weights, max_bits, used) = read_weights(data)?;
+        let mut bits = [0; 256];
+        for (s, &weight) in weights.iter().enumerate().filter(|(_, &w)| w != 0) {
+            bits[s] = max_bits + 1 - weight;
+        }
+        let mut table = Self {
+            codes: [0; 256],
+            bits,
+            max_bits,
+            last: weights.len() - 1,
+        };
+        table.assign_codes();
+        Ok((table, used))
+    }
+
+    /// Canonical codes: longest codes first, in symbol order.
+    fn assign_codes(&mut self) {
+        let mut rank_start = [0u32; MAX_LOG as usize + 2];
+        let mut position = 0;
+        for weight in 1..=self.max_bits {
+            rank_start[usize::from(weight)] = position;
+            for s in 0..=self.last {
+                if self.weight(s) == weight {
+                    position += 1 << (weight - 1);
+                }
+            }
+        }
+        for s in 0..=self.last {
+            let weight = self.weight(s);
+            if weight != 0 {
+                let start = &mut rank_start[usize::from(weight)];
+                self.codes[s] = (*start >> (weight - 1)) as u16;
+                *start += 1 << (weight - 1);
+            }
+        }
+    }
+
+    /// The weight of a symbol, as written in the table description.
+    fn weight(&self, symbol: usize) -> u8 {
+        match self.bits[symbol] {
+            0 => 0,
+            bits => self.max_bits + 1 - bits,
+        }
+    }
+
+    /// Estimated size in bytes of the literals encoded with this table, or
+    /// `None` if some literal has no code.
+    pub(crate) fn cost(&self, counts: &[u32; 256]) -> Option<usize> {
+        let mut bits = 0;
+        for (s, &count) in counts.iter().enumerate() {
+            if count != 0 {
+                if self.bits[s] == 0 {
+                    return None;
+                }
+                bits += count as usize * usize::from(self.bits[s]);
+            }
+        }
+        Some(bits.div_ceil(8))
+    }
+
+    /// Write the table description.  Returns `false` if neither the direct
+    /// nor the FSE-compressed representation can hold the weights.
+    pub(crate) fn describe(&self, out: &mut Vec<u8>) -> bool {
+        // The weight of the last symbol is implied.
+        let weights: Vec<u8> = (0..self.last).map(|s| self.weight(s)).collect();
+        let direct = if weights.len() <= 128 {
+            let mut direct = vec![127 + weights.len() as u8];
+            for pair in weights.chunks(2) {
+                direct.push(pair[0] << 4 | pair.get(1).cloned().unwrap_or(0));
+            }
+            Some(direct)
+        } else {
+            None
+        };
+        let compressed = compress_weights(&weights, self.max_bits);
+        let best = match (direct, compressed) {
+            (Some(d), Some(c)) if c.len() < d.len() => Some(c),
+            (Some(d), _) => Some(d),
+            (None, c) => c,
+        };
+        match best {
+            Some(best) => {
+                out.extend_from_slice(&best);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Encode literals as a single stream.
+    pub(crate) fn encode(&self, literals: &[u8], out: &mut Vec<u8>) {
+        let mut writer = BitWriter::new();
+        for &literal in literals.iter().rev() {
+            let literal = usize::from(literal);
+            writer.add(self.codes[literal].into(), self.bits[literal].into());
+        }
+        writer.finish(out);
+    }
+
+    /// Encode literals as four streams, preceded by the jump table.
+    /// Returns `false` if a stream is too large for the jump table.
+    pub(crate) fn encode4(&self, literals: &[u8], out: &mut Vec<u8>) -> bool {
+        let segment = literals.len().div_ceil(4);
+        let jump_table = out.len();
+        out.extend_from_slice(&[0; 6]);
+        for (i, stream) in literals.chunks(segment).enumerate() {
+            let start = out.len();
+            self.encode(stream, out);
+            let size = out.len() - start;
+            if i < 3 {
+                if size > usize::from(u16::MAX) {
+                    return false;
+                }
+                out[jump_table + i * 2..][..2].copy_from_slice(&(size as u16).to_le_bytes());
+            }
+        }
+        true
+    }
+}
+
+/// A Huffman decoding table for literals.
+#[derive(Clone)]
+pub(crate) struct HuffmanDecoder {
+    max_bits: u8,
+    /// Per `max_bits` bit prefix: (symbol, code length).
+    entries: Vec<(u8, u8)>,
+}
+
+impl HuffmanDecoder {
+    /// Read a table description: (table, bytes read).
+    pub(crate) fn read(data: &[u8]) -> Result<(Self, usize), DecError> {
+        let (weights, max_bits, used) = read_weights(data)?;
+        let mut entries = vec![(0, 0); 1 << max_bits];
+        let mut position = 0;
+        for weight in 1..=max_bits {
+            for (s, _) in weights.iter().enumerate().filter(|(_, &w)| w == weight) {
+                let run = 1 << (weight - 1);
+                let entry = (s as u8, max_bits + 1 - weight);
+                entries[position..position + run].iter_mut().for_each(|e| *e = entry);
+                position += run;
+            }
+        }
+        Ok((Self { max_bits, entries }, used))
+    }
+
+    /// Decode `len` literals from a single stream.
+    pub(crate) fn decode(&self, stream: &[u8], len: usize, out: &mut Vec<u8>) -> Result<(), DecError> {
+        let mut reader = BitReader::new(stream).ok_or(DecError::Literals)?;
+        for _ in 0..len {
+            let (symbol, bits) = self.entries[reader.peek(self.max_bits.into()) as usize];
+            reader.consume(bits.into());
+            out.push(symbol);
+        }
+        if !reader.finished() {
+            return Err(DecError::Literals);
+        }
+        Ok(())
+    }
+
+    /// Decode `len` literals from four streams, preceded by the jump table.
+    pub(crate) fn decode4(&self, data: &[u8], len: usize, out: &mut Vec<u8>) -> Result<(), DecError> {
+        if data.len() < 6 {
+            return Err(DecError::Literals);
+        }
+        let mut streams = &data[6..];
+        let segment = len.div_ceil(4);
+        for i in 0..4 {
+            let size = match i {
+                3 => streams.len(),
+                _ => usize::from(u16::from_le_bytes([data[i * 2], data[i * 2 + 1]])),
+            };
+            if size > streams.len() || segment * i > len {
+                return Err(DecError::Literals);
+            }
+            self.decode(&streams[..size], segment.min(len - segment * i), out)?;
+            streams = &streams[size..];
+        }
+        Ok(())
+    }
+}
+
+/// Read a table description: (the weight of every symbol up to the last
+/// one with a code, longest code length, bytes read).
+fn read_weights(data: &[u8]) -> Result<(Vec<u8>, u8, usize), DecError> {
+    let header = usize::from(*data.first().ok_or(DecError::Literals)?);
+    let (mut weights, used) = if header >= 128 {
+        let count = header - 127;
+        let bytes = data.get(1..1 + count.div_ceil(2)).ok_or(DecError::Literals)?;
+        let weights = bytes.iter().flat_map(|&byte| [byte >> 4, byte & 0xF]);
+        (weights.take(count).collect(), 1 + count.div_ceil(2))
+    } else {
+        let body = data.get(1..1 + header).ok_or(DecError::Literals)?;
+        (decompress_weights(body)?, 1 + header)
+    };
+
+    // The weight of the last symbol makes the total a power of two.
+    let total: u32 = weights.iter().filter(|&&w| w != 0).map(|&w| 1 << (w - 1)).sum();
+    if total == 0 || weights.len() > 255 {
+        return Err(DecError::Literals);
+    }
+    let max_bits = fse::highbit(total) as u8 + 1;
+    let rest = (1 << max_bits) - total;
+    if max_bits > MAX_LOG || !rest.is_power_of_two() {
+        return Err(DecError::Literals);
+    }
+    weights.push(fse::highbit(rest) as u8 + 1);
+    Ok((weights, max_bits, used))
+}
+
+/// Decode FSE-compressed weights.
+fn decompress_weights(data: &[u8]) -> Result<Vec<u8>, DecError> {
+    let (norm, log, used) = fse::read_header(data, usize::from(MAX_LOG) + 1, WEIGHTS_MAX_LOG)?;
+    let table = FseDecoder::new(&norm, log);
+    let mut reader = BitReader::new(&data[used..]).ok_or(DecError::Literals)?;
+    let mut state1 = table.start(&mut reader);
+    let mut state2 = table.start(&mut reader);
+    let mut weights = Vec::new();
+    // Alternate between the states until the stream runs out.
+    loop {
+        weights.push(table.symbol(state1));
+        state1 = table.update(state1, &mut reader);
+        if reader.overflowed() {
+            weights.push(table.symbol(state2));
+            break;
+        }
+        weights.push(table.symbol(state2));
+        state2 = table.update(state2, &mut reader);
+        if reader.overflowed() {
+            weights.push(table.symbol(state1));
+            break;
+        }
+        if weights.len() > 255 {
+            return Err(DecError::Literals);
+        }
+    }
+    Ok(weights)
+}
+
+/// FSE-compress the weights, including the size byte.
+fn compress_weights(weights: &[u8], max_weight: u8) -> Option<Vec<u8>> {
+    let mut counts = vec![0u32; usize::from(max_weight) + 1];
+    for &w in weights {
+        counts[usize::from(w)] += 1;
+    }
+    // A single repeated weight isn't worth (or able to be) FSE coded.
+    if weights.len() < 2 || counts.iter().any(|&c| c as usize == weights.len()) {
+        return None;
+    }
+    let log = fse::optimal_log(WEIGHTS_MAX_LOG, weights.len(), counts.len() - 1);
+    let norm = fse::normalize(&counts, log);
+    let table = FseTable::new(&norm, log);
+
+    let mut out = vec![0];
+    fse::write_header(&norm, log, &mut out);
+    let mut writer = BitWriter::new();
+    let mut i = weights.len();
+    let (mut state1, mut state2);
+    if i & 1 != 0 {
+        state1 = table.start(weights[i - 1]);
+        state2 = table.start(weights[i - 2]);
+        state1.encode(&mut writer, weights[i - 3]);
+        i -= 3;
+    } else {
+        state2 = table.start(weights[i - 1]);
+        state1 = table.start(weights[i - 2]);
+        i -= 2;
+    }
+    while i > 0 {
+        state2.encode(&mut writer, weights[i - 1]);
+        state1.encode(&mut writer, weights[i - 2]);
+        i -= 2;
+    }
+    state2.flush(&mut writer);
+    state1.flush(&mut writer);
+    writer.finish(&mut out);
+
+    if out.len() - 1 > 127 {
+        return None;
+    }
+    out[0] = (out.len() - 1) as u8;
+    Some(out)
+}
+
+/// Optimal code lengths no longer than `limit`, using package-merge.
+fn lengths(counts: &[u32; 256], limit: u8) -> Option<[u8; 256]> {
+    enum Node {
+        Leaf(usize),
+        Package(usize, usize),
+    }
+
+    let mut leaves: Vec<(u64, usize)> = counts
+        .iter()
+        .enumerate()
+        .filter(|(_, &c)| c != 0)
+        .map(|(s, &c)| (u64::from(c), s))
+        .collect();
+    if leaves.len() < 2 {
+        return None;
+    }
+    leaves.sort();
+
+    let mut nodes: Vec<Node> = leaves.iter().map(|&(_, s)| Node::Leaf(s)).collect();
+    let leaf_items: Vec<(u64, usize)> = leaves.iter().enumerate().map(|(i, &(w, _))| (w, i)).collect();
+    let mut items = leaf_items.clone();
+    for _ in 1..limit {
+        let mut packages = Vec::with_capacity(items.len() / 2);
+        for pair in items.chunks_exact(2) {
+            nodes.push(Node::Package(pair[0].1, pair[1].1));
+            packages.push((pair[0].0 + pair[1].0, nodes.len() - 1));
+        }
+        // Merge, keeping leaves first when weights are equal.
+        let mut merged = Vec::with_capacity(leaf_items.len() + packages.len());
+        let (mut a, mut b) = (0, 0);
+        while a < leaf_items.len() || b < packages.len() {
+            if b == packages.len() || (a < leaf_items.len() && leaf_items[a].0 <= packages[b].0) {
+                merged.push(leaf_items[a]);
+                a += 1;
+            } else {
+                merged.push(packages[b]);
+                b += 1;
+            }
+        }
+        items = merged;
+    }
+
+    let mut bits = [0u8; 256];
+    let mut stack: Vec<usize> = items[..2 * leaves.len() - 2].iter().map(|&(_, n)| n).collect();
+    while let Some(node) = stack.pop() {
+        match nodes[node] {
+            Node::Leaf(s) => bits[s] += 1,
+            Node::Package(a, b) => {
+                stack.push(a);
+                stack.push(b);
+            }
+        }
+    }
+    Some(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lengths, HuffmanDecoder, HuffmanTable};
+
+    #[test]
+    fn complete_limited_code() {
+        // Fibonacci counts make the unlimited code much deeper than 11.
+        let mut counts = [0u32; 256];
+        let (mut a, mut b) = (1, 1);
+        for count in counts.iter_mut().take(30) {
+            *count = a;
+            let c = a + b;
+            a = b;
+            b = c;
+        }
+        let bits = lengths(&counts, 11).unwrap();
+        assert!(bits.iter().all(|&b| b <= 11));
+        let kraft: u32 = bits.iter().filter(|&&b| b != 0).map(|&b| 1 << (11 - b)).sum();
+        assert_eq!(kraft, 1 << 11);
+    }
+
+    #[test]
+    fn single_symbol() {
+        let mut counts = [0u32; 256];
+        counts[b'a' as usize] = 10;
+        assert!(HuffmanTable::new(&counts).is_none());
+    }
+
+    #[test]
+    fn description() {
+        let mut counts = [0u32; 256];
+        counts[0] = 3;
+        counts[1] = 1;
+        counts[2] = 1;
+        let table = HuffmanTable::new(&counts).unwrap();
+        let mut out = Vec::new();
+        assert!(table.describe(&mut out));
+        // Two direct weights: 2 for symbol 0, 1 for symbol 1.
+        assert_eq!(out, [129, 0x21]);
+    }
+
+    #[test]
+    fn decode_encoded_literals() {
+        let literals: Vec<u8> = (0..3000u32).map(|i| (i * i % 61 % 23) as u8 * 3).collect();
+        let mut counts = [0u32; 256];
+        for &literal in &literals {
+            counts[usize::from(literal)] += 1;
+        }
+        let table = HuffmanTable::new(&counts).unwrap();
+        let mut out = Vec::new();
+        assert!(table.describe(&mut out));
+        let described = out.len();
+        assert!(table.encode4(&literals, &mut out));
+
+        let (decoder, used) = HuffmanDecoder::read(&out).unwrap();
+        assert_eq!(used, described);
+        let mut decoded = Vec::new();
+        decoder.decode4(&out[used..], literals.len(), &mut decoded).unwrap();
+        assert_eq!(decoded, literals);
+    }
+}
@@ -0,0 +1,205 @@
+//! Long distance matching: a rolling hash picks positions to remember all
+//! across a large window, so repeats far apart are found cheaply.  The
+//! gaps between long matches are left to the regular match finder.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/compress/zstd_ldm.c
+
+use super::matcher::{self, MatchFinder};
+use super::params::CompressionParams;
+use super::sequence::Sequence;
+use super::xxhash::Xxh64;
+
+/// Shortest long distance match, and the length hashed at each position.
+const MIN_MATCH: usize = 64;
+/// Log2 of the entries in a bucket.
+const BUCKET_LOG: u8 = 3;
+/// Log2 of the average distance between remembered positions.
+const HASH_RATE_LOG: u8 = 7;
+
+/// Random values for the gear rolling hash.
+pub(crate) const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < 256 {
+        // SplitMix64.
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Finds long matches at positions where a rolling hash of the data before
+/// them has its top bits clear, then runs another match finder between them.
+pub(crate) struct LongDistanceMatcher {
+    /// Buckets of (position, checksum) pairs.
+    table: Vec<(u32, u32)>,
+    /// Slot to replace next in each bucket.
+    heads: Vec<u8>,
+    window: usize,
+    /// Gear hash of the data before `next`.
+    gear: u64,
+    next: usize,
+    inner: Box<dyn MatchFinder>,
+    matches: Vec<Sequence>,
+}
+
+impl LongDistanceMatcher {
+    /// Create a long distance match finder, running `inner` between the
+    /// long matches.
+    pub(crate) fn new(params: &CompressionParams, inner: Box<dyn MatchFinder>) -> Self {
+        let window_log = (63 - (params.window() as u64).leading_zeros()) as u8;
+        let bucket_log = window_log.saturating_sub(HASH_RATE_LOG).max(6) - BUCKET_LOG;
+        Self {
+            table: vec![(0, 0); 1 << (bucket_log + BUCKET_LOG)],
+            heads: vec![0; 1 << bucket_log],
+            window: params.window(),
+            gear: 0,
+            next: 0,
+            inner,
+            matches: Vec::new(),
+        }
+    }
+
+    /// Find long matches in `data[start..end]`, remembering positions as it
+    /// goes.  Literal lengths count from the end of the previous match.
+    fn find_long(&mut self, data: &[u8], start: usize, end: usize) {
+        const STOP_MASK: u64 = ((1 << HASH_RATE_LOG) - 1) << (64 - HASH_RATE_LOG);
+        let buckets = self.heads.len();
+        let mut anchor = start;
+        self.matches.clear();
+
+        for ip in self.next.max(start)..end {
+            self.gear = (self.gear << 1).wrapping_add(GEAR[usize::from(data[ip])]);
+            if self.gear & STOP_MASK != 0 || ip + 1 < MIN_MATCH {
+                continue;
+            }
+            let split = ip + 1 - MIN_MATCH;
+            let mut hasher = Xxh64::new();
+            hasher.update(&data[split..=ip]);
+            let hash = hasher.digest();
+            let (bucket, checksum) = (hash as usize & (buckets - 1), (hash >> 32) as u32);
+            let entries = bucket << BUCKET_LOG..(bucket + 1) << BUCKET_LOG;
+
+            // Positions inside the last match are only remembered.
+            let (mut best_len, mut best_back, mut best_offset) = (0, 0, 0);
+            if split >= anchor {
+                for &(candidate, entry_checksum) in &self.table[entries.clone()] {
+                    let candidate = candidate as usize;
+                    if entry_checksum != checksum
+                        || candidate >= split
+                        || split - candidate > self.window
+                    {
+                        continue;
+                    }
+                    let forward = matcher::match_len(data, candidate, split, end);
+                    if forward < MIN_MATCH {
+                        continue;
+                    }
+                    let mut back = 0;
+                    while back < split - anchor
+                        && back < candidate
+                        && data[candidate - back - 1] == data[split - back - 1]
+                    {
+                        back += 1;
+                    }
+                    if forward + back > best_len {
+                        best_len = forward + back;
+                        best_back = back;
+                        best_offset = split - candidate;
+                    }
+                }
+            }
+
+            let head = &mut self.heads[bucket];
+            self.table[entries.start + usize::from(*head)] = (split as u32, checksum);
+            *head = (*head + 1) & ((1 << BUCKET_LOG) - 1);
+
+            if best_len != 0 {
+                let match_start = split - best_back;
+                self.matches.push(Sequence {
+                    lit_len: (match_start - anchor) as u32,
+                    match_len: best_len as u32,
+                    offset: best_offset as u32,
+                });
+                anchor = match_start + best_len;
+            }
+        }
+        self.next = end;
+    }
+}
+
+impl MatchFinder for LongDistanceMatcher {
+    fn find(&mut self, data: &[u8], start: usize, end: usize, sequences: &mut Vec<Sequence>) {
+        self.find_long(data, start, end);
+        let mut pos = start;
+        for i in 0..self.matches.len() {
+            let long = self.matches[i];
+            let match_start = pos + long.lit_len as usize;
+            // Fill the gap before the long match, which takes over the
+            // literals left at the end.
+            let first = sequences.len();
+            self.inner.find(data, pos, match_start, sequences);
+            let covered: u32 =
+                sequences[first..].iter().map(|seq| seq.lit_len + seq.match_len).sum();
+            sequences.push(Sequence { lit_len: long.lit_len - covered, ..long });
+            pos = match_start + long.match_len as usize;
+        }
+        self.inner.find(data, pos, end, sequences);
+    }
+
+    fn slide(&mut self, amount: usize) {
+        let amount32 = amount as u32;
+        for (pos, _) in &mut self.table {
+            *pos = pos.saturating_sub(amount32);
+        }
+        self.next -= amount;
+        self.inner.slide(amount);
+    }
+
+    /// Long matches are still remembered, since finding later copies of
+    /// incompressible data is what they're best at.
+    fn skip(&mut self, data: &[u8], start: usize, end: usize) {
+        self.find_long(data, start, end);
+        self.inner.skip(data, start, end);
+    }
+
+    fn fill(&mut self, data: &[u8], start: usize, end: usize) {
+        self.find_long(data, start, end);
+        self.inner.fill(data, start, end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::matcher::{self, tests::execute};
+    use crate::params::CompressionParams;
+    use crate::tests::noise;
+
+    #[test]
+    fn finds_distant_repeats() {
+        // Noise, then the first half of it again a mebibyte later.
+        let mut data = noise(1 << 20, 1);
+        data.extend_from_within(..1 << 19);
+
+        let params = CompressionParams::level(1).long_distance(true);
+        let mut matcher = matcher::new(&params);
+        let mut sequences = Vec::new();
+        for start in (0..data.len()).step_by(1 << 17) {
+            let mut block = Vec::new();
+            matcher.find(&data, start, start + (1 << 17), &mut block);
+            assert_eq!(
+                execute(&data, start, start + (1 << 17), &block),
+                &data[..start + (1 << 17)]
+            );
+            sequences.extend(block);
+        }
+        let distant: u32 =
+            sequences.iter().filter(|seq| seq.offset == 1 << 20).map(|seq| seq.match_len).sum();
+        assert!(distant > (1 << 19) - 4096);
+    }
+}
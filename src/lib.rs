@@ -9,109 +9,69 @@
     html_root_url = "https://docs.rs/zstandard"
 )]
 
-// FIXME
-#![allow(unreachable_code)]
-
-use std::convert::TryInto;
 use std::error::Error;
 use std::io::{Read, Write, Error as IoErr, ErrorKind as Kind};
 use std::fmt::{Display, Formatter, Error as FmtError};
-use std::cmp::Ord;
 
 mod encoder;
 mod decoder;
 mod parser;
+mod header;
+mod frames;
+mod params;
+mod bitstream;
+mod fse;
+mod huffman;
+mod sequence;
+mod matcher;
+mod fast;
+mod row;
+mod double_fast;
+mod hash_chain;
+mod binary_tree;
+mod optimal;
+mod ldm;
+mod block;
+mod split;
+mod parallel;
+mod adapt;
+mod oneshot;
+mod seekable;
+mod dictionary;
+mod xxhash;
 
 pub use decoder::Decoder;
-pub use encoder::Encoder;
+pub use dictionary::Dictionary;
+pub use encoder::{compress_to_fit, AutoFinishEncoder, Encoder, EncoderStats};
+pub use frames::{find_frames, read_frames, FrameInfo};
+pub use header::{FrameHeader, FrameType};
+pub use oneshot::{compress, compress_bound, decompress, decompress_into, decompress_with_limit};
+pub use params::{CompressionParams, Strategy};
+pub use seekable::{SeekableDecoder, SeekableEncoder};
 use parser::LeDecoder;
-
-/*
- *
- *
-*/
-
-// A tree of prefix codes.
-struct HuffmanTreeBuilder {
-    literal: u8,
-    // List of weight, value.
-    list: Vec<(u8, u8)>,
-}
-
-impl HuffmanTreeBuilder {
-    /// Create an empty huffman tree builder.
-    pub fn new() -> Self {
-        Self {
-            literal: 0,
-            list: Vec::new(),
-        }
-    }
-
-    /// Add a weight for the next literal.
-    pub fn weight(&mut self, weight: u8) {
-        if weight != 0 {
-            self.list.push((weight, self.literal));
-        }
-        self.literal += 1;
-    }
-
-    // FIXME https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#representation
-    /*/// Finish building the Huffman tree.
-    pub fn finish(self) -> HuffmanTree {
-        // Stable sort by weight, secondary sort by natural seq. order stays.
-        self.list.sort_by(|a, b| a.0.cmp(b.0));
-        // Initialize empty huffman tree.
-        let mut tree = HuffmanTree {
-            list: Vec::new(),
-        };
-        // 
-        let mut old_weight = 0;
-        let mut counter = 0;
-        for (weight, value) in self.list {
-            number_of_bits(max_bits, weight);
-        }
-        // Return the created tree
-        tree
-    }*/
-}
-
-struct HuffmanTree {
-    // List of bits, bit sequence, value.
-    list: Vec<(u8, u8, u8)>,
-}
-
-// Get the number of bits for a weight.
-fn number_of_bits(max_bits: u8, weight: u8) -> u8 {
-    if weight > 0 {
-        max_bits + 1 - weight
-    } else {
-        0
-    }
-}
-
-fn huffman_stream(stream: &[u8]) {
-    let mut stream = stream.iter();
-    while let Some(byte) = stream.next_back() {
-        todo!()
-    }
-}
+use block::BlockDecoder;
+use params::BLOCK_SIZE_MAX;
+use xxhash::Xxh64;
 
 // ZStd magic number.
 const MAGIC_NUMBER: u32 = 0xFD2FB528;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Copy, Clone)]
 enum BlockType {
-    RawBlock,
-    RleBlock,
-    ZstdBlock,
+    Raw = 0,
+    Rle = 1,
+    Compressed = 2,
 }
 
-#[derive(PartialEq)]
-enum LiteralType {
-    Raw,
-    Rle,
-    HuffmanTree,
-    HuffmanTreeless,
+/// Write a block header.
+fn write_block_header<W: Write>(
+    writer: &mut W,
+    last: bool,
+    block_type: BlockType,
+    size: usize,
+) -> Result<(), IoErr> {
+    let header = (size as u32) << 3 | (block_type as u32) << 1 | last as u32;
+    writer.write_all(&header.to_le_bytes()[..3])
 }
 
 /// Decoder Error.
@@ -120,8 +80,16 @@ enum DecError {
     MagicNumber,
     FrameHeaderDesc,
     WindowSize,
-    NoBlocks,
     InvalidBlockType,
+    BlockSize,
+    Literals,
+    Table,
+    Sequences,
+    Offset,
+    ContentSize,
+    Checksum,
+    Dictionary,
+    SeekTable,
 }
 
 impl Display for DecError {
@@ -131,8 +99,16 @@ impl Display for DecError {
             MagicNumber => "Magic number does not match",
             FrameHeaderDesc => "Invalid values in the frame header descriptor.",
             WindowSize => "Window size is too large or too small.",
-            NoBlocks => "There were no blocks in the frame.",
             InvalidBlockType => "Block type is invalid (reserved value used).",
+            BlockSize => "Block is larger than the maximum block size.",
+            Literals => "Literals section is invalid.",
+            Table => "Entropy table description is invalid.",
+            Sequences => "Sequences section is invalid.",
+            Offset => "Match offset reaches before the start of the data.",
+            ContentSize => "Frame content size does not match the decoded data.",
+            Checksum => "Content checksum does not match the decoded data.",
+            Dictionary => "Dictionary is invalid or not the one the frame needs.",
+            SeekTable => "Seek table is missing or does not match the frames.",
         };
         write!(f, "{}", message)
     }
@@ -149,307 +125,579 @@ impl From<DecError> for IoErr {
 
 #[derive(Default)]
 struct Frame {
+    /// Window of history (starting with any dictionary content), followed
+    /// by decoded bytes not yet read.
     data: Vec<u8>,
+    /// Position of the next byte to read out of `data`.
+    pos: usize,
+    /// Whether the frame has blocks left to decode.
+    active: bool,
+    window: usize,
+    /// Size of the dictionary content, which the window starts after.
+    dictionary: usize,
+    block_max: usize,
+    blocks: BlockDecoder,
+    /// The compressed block being decoded.
+    block: Vec<u8>,
+    content_size: Option<u64>,
+    /// Bytes of content decoded so far.
+    decoded: u64,
+    hasher: Option<Xxh64>,
 }
 
 impl Frame {
-    fn encode<W: Write>(&mut self, writer: &mut W) -> Result<(), IoErr> {
+    /// Decoded bytes not yet read.
+    fn unread(&self) -> &[u8] {
+        &self.data[self.pos..]
+    }
+
+    /// Decode the header of the next frame, passing over skippable frames.
+    /// Returns `false` at the end of the input.  Frames whose window is
+    /// larger than `1 << window_log_max` are rejected.
+    fn start<R: Read>(
+        &mut self,
+        reader: &mut R,
+        window_log_max: u8,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<bool, IoErr> {
+        self.data.clear();
+        self.pos = 0;
+
         ///////////////////// Magic_Number ////////////////////
 
-        let data = &self.data[..];
-        writer.write_all(&MAGIC_NUMBER.to_le_bytes())?;
-
-        ///////////////////// Frame_Header ////////////////////
-        
-        // Encode frame header descriptor.
-        let mut frame_head_desc = 0b0110_0000;
-        // 16 bit Frame Content Size
-        // Single segment
-        // No Checksum
-        // No Dictionary
-        writer.write_all(&[frame_head_desc])?;
-        
-        ///////////////////// Data_Block(s) ////////////////////
-        
-        todo!();
-        
-        ///////////////////// Content_Checksum ////////////////////
+        // Running out of input between frames is the normal end, and
+        // skippable frames are passed over.
+        let header = loop {
+            let header = match FrameHeader::read_next(reader)? {
+                Some(header) => header,
+                None => return Ok(false),
+            };
+            match (header.frame_type, header.content_size) {
+                (FrameType::Skippable(_), Some(size)) => {
+                    let skipped = std::io::copy(&mut reader.take(size), &mut std::io::sink())?;
+                    if skipped != size {
+                        Err(IoErr::from(Kind::UnexpectedEof))?
+                    }
+                }
+                _ => break header,
+            }
+        };
+        let dictionary_id = header.dictionary_id;
+        if dictionary_id.is_some() && dictionary_id != dictionary.map(Dictionary::id) {
+            Err(DecError::Dictionary)?
+        }
+        if header.window_size > 1 << window_log_max {
+            Err(DecError::WindowSize)?
+        }
+        self.window = header.window_size as usize;
+        self.block_max = self.window.min(BLOCK_SIZE_MAX);
+        self.content_size = header.content_size;
+        self.decoded = 0;
+        self.hasher = if header.checksum { Some(Xxh64::new()) } else { None };
+
+        // Matches can refer to the dictionary content as if it came just
+        // before the frame.
+        self.blocks = BlockDecoder::new();
+        self.dictionary = 0;
+        if let Some(dictionary) = dictionary {
+            self.data.extend_from_slice(dictionary.content());
+            self.pos = self.data.len();
+            self.dictionary = self.pos;
+            self.blocks = BlockDecoder::with_dictionary(dictionary);
+        }
+        self.active = true;
+        Ok(true)
+    }
 
-        todo!();
+    /// Decode the next block, after all of the earlier ones have been read.
+    /// The checksum is checked after the last block.
+    fn decode_block<R: Read>(&mut self, reader: &mut R) -> Result<(), IoErr> {
+        // Drop history the window no longer reaches, a window's worth at a
+        // time.  Matches reach the dictionary until the content alone fills
+        // the window (and always do in a single segment frame, whose window
+        // is its content size).
+        let excess = self.pos.saturating_sub(self.dictionary + self.window);
+        if excess > self.window.max(BLOCK_SIZE_MAX) {
+            self.data.drain(..excess);
+            self.pos -= excess;
+        }
+        let mut dec = LeDecoder::new(reader);
 
-        self.data.clear();
+        ///////////////////// Data_Block ////////////////////
+
+        let block_header = dec.u24()?;
+        let last_block = block_header & 1 != 0;
+        let block_size = (block_header >> 3) as usize;
+        let block_type = match block_header >> 1 & 0b11 {
+            0 => BlockType::Raw,
+            1 => BlockType::Rle,
+            2 => BlockType::Compressed,
+            _ => Err(DecError::InvalidBlockType)?,
+        };
+        if block_size > self.block_max {
+            Err(DecError::BlockSize)?
+        }
+        let block_start = self.data.len();
+        match block_type {
+            BlockType::Raw => {
+                self.data.resize(block_start + block_size, 0);
+                dec.bytes(&mut self.data[block_start..])?;
+            }
+            BlockType::Rle => {
+                let byte = dec.u8()?;
+                self.data.resize(block_start + block_size, byte);
+            }
+            BlockType::Compressed => {
+                self.block.resize(block_size, 0);
+                dec.bytes(&mut self.block)?;
+                self.blocks.decode(&self.block, &mut self.data, self.block_max)?;
+            }
+        }
+        self.decoded += (self.data.len() - block_start) as u64;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&self.data[block_start..]);
+        }
+        if !last_block {
+            return Ok(());
+        }
+        self.active = false;
+
+        if self.content_size.is_some_and(|size| size != self.decoded) {
+            Err(DecError::ContentSize)?
+        }
+
+        ///////////////////// Content_Checksum ////////////////////
+
+        if let Some(hasher) = self.hasher.take() {
+            if dec.u32()? != hasher.digest() as u32 {
+                Err(DecError::Checksum)?
+            }
+        }
         Ok(())
     }
-    
-    fn decode<R: Read>(&mut self, reader: &mut R) -> Result<(), IoErr> {
-        let mut dec = LeDecoder::new(reader);
+}
 
-        ///////////////////// Magic_Number ////////////////////
+#[cfg(test)]
+mod tests {
+    use super::{compress_to_fit, CompressionParams, Decoder, Encoder};
+    use std::cell::RefCell;
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(2 + 2, 4);
+    }
+
+    /// Numbers from a linear congruential generator, for noise or for
+    /// picking words at random.
+    pub(crate) fn random(seed: u32) -> impl Iterator<Item = u32> {
+        let next = |state: &u32| Some(state.wrapping_mul(1_103_515_245).wrapping_add(12345));
+        std::iter::successors(next(&seed), next)
+    }
+
+    /// `len` bytes that don't compress.
+    pub(crate) fn noise(len: usize, seed: u32) -> Vec<u8> {
+        random(seed).take(len).map(|state| (state >> 16) as u8).collect()
+    }
 
-        if dec.u32()? != MAGIC_NUMBER {
-            Err(DecError::MagicNumber)?
+    /// Text, a run of one byte and noise.
+    pub(crate) fn sample() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..30_000u32 {
+            data.extend_from_slice(format!("{} {} {};", i % 251, i % 17, i % 1000).as_bytes());
         }
+        data.extend_from_slice(&[7; 5000]);
+        data.extend(noise(20_000, 1));
+        data
+    }
 
-        ///////////////////// Frame_Header ////////////////////
-
-        // Decode the frame header descriptor.
-        let frame_head_desc = dec.u8()?;
-        let frame_content_size_flag = frame_head_desc & 0b1100_0000;
-        let single_segment_flag = frame_head_desc & 0b0010_0000;
-        let unused_reserved_bits = frame_head_desc & 0b0001_1000;
-        let content_checksum_flag = frame_head_desc & 0b0000_0100;
-        let dictionary_id_flag = frame_head_desc & 0b0000_0011;
-        // Interpret frame header descriptor.
-        let fcs_field_size = match frame_content_size_flag {
-            0b0000_0000 => single_segment_flag >> 5,
-            0b0100_0000 => 2,
-            0b1000_0000 => 4,
-            0b1100_0000 => 8,
-            _ => unreachable!(),
-        };
-        if unused_reserved_bits != 0 {
-            Err(DecError::FrameHeaderDesc)?
+    pub(crate) fn compress(data: &[u8], params: CompressionParams) -> Vec<u8> {
+        compress_with_workers(data, params, 0)
+    }
+
+    /// Compress `data` with `workers` threads, writing it in pieces.
+    pub(crate) fn compress_with_workers(
+        data: &[u8],
+        params: CompressionParams,
+        workers: usize,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = Encoder::new(&mut out).params(params).workers(workers);
+        for chunk in data.chunks(100_000) {
+            encoder.write_all(chunk).unwrap();
         }
-        let content_checksum = content_checksum_flag != 0;
-
-        // Check for window descriptor if it exists.
-        let window_size: Option<u64> = if single_segment_flag == 0 {
-            let window_descriptor: u64 = dec.u8()?.into();
-            let exponent = (window_descriptor & 0b1111_1000) >> 3;
-            let mantissa = window_descriptor & 0b0000_0111;
-            let window_log = 10 + exponent;
-            let window_base = 1 << window_log;
-            let window_add = (window_base / 8) * mantissa;
-
-            Some(window_base + window_add)
-        } else {
-            None
-        };
+        encoder.finish().unwrap();
+        out
+    }
 
-        // Check dictionary ID field.
-        let dictionary_id: Option<u32> = match dictionary_id_flag {
-            0 => None,
-            1 => {
-                let did = dec.u8()?.into();
-                Some(did)
-            },
-            2 => {
-                let did = dec.u16()?.into();
-                Some(did)
-            },
-            3 => {
-                let did = dec.u32()?;
-                Some(did)
-            },
-            _ => unreachable!(),
-        };
+    pub(crate) fn decompress(frames: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        Decoder::new(frames).window_log_max(31).read_to_end(&mut out).unwrap();
+        out
+    }
 
-        // Check frame content size.
-        let window_size: u64 = if let Some(window_size) = window_size {
-            window_size
-        } else {
-            let window_size: u64 = match fcs_field_size {
-                1 => dec.u8()?.into(),
-                2 => dec.u16()?.into(),
-                4 => dec.u32()?.into(),
-                8 => dec.u64()?,
-                _ => unreachable!(),
-            };
-            window_size
-        };
+    #[test]
+    fn round_trip() {
+        let data = sample();
+        for &level in &[-5, 1, 3, 5, 8, 13, 16, 19] {
+            let frames = compress(&data, CompressionParams::level(level));
+            assert!(frames.len() < data.len() / 2);
+            assert_eq!(decompress(&frames), data);
+        }
+    }
 
-        // Support From 1KB to 8MB
-        if window_size > 8_000_000 || window_size < 1_000 {
-            Err(DecError::WindowSize)?
+    #[test]
+    fn round_trip_frames() {
+        // More data than the window, then an empty frame and another one.
+        let data = sample();
+        let mut frames = compress(&data, CompressionParams::level(3).window_log(16));
+        let empty = Encoder::new(Vec::new()).finish().unwrap();
+        assert_eq!(empty.len(), 13);
+        frames.extend_from_slice(&empty);
+        frames.extend_from_slice(&compress(&data[..1000], CompressionParams::level(1)));
+        assert_eq!(decompress(&frames), [&data[..], &data[..1000]].concat());
+        assert!(decompress(&[]).is_empty());
+    }
+
+    /// A writer that can be read while an encoder holds it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
         }
 
-        // Resize buffer (to be overwritten)
-        self.data.resize(window_size.try_into().unwrap(), 0);
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
 
-        ///////////////////// Data_Block(s) ////////////////////
+    #[test]
+    fn flush() {
+        let data = sample();
+        for &workers in &[0, 2] {
+            let out = Shared::default();
+            let mut encoder = Encoder::new(out.clone()).workers(workers);
+            let mut decoded = Vec::new();
+            for chunk in data.chunks(50_000) {
+                encoder.write_all(chunk).unwrap();
+                encoder.flush().unwrap();
+                // Everything written so far can be read back.
+                let frame = out.0.borrow().clone();
+                let mut decoder = Decoder::new(&frame[..]);
+                decoded.resize(decoded.len() + chunk.len(), 0);
+                decoder.read_exact(&mut decoded).unwrap();
+            }
+            assert_eq!(decoded, data);
+            encoder.finish().unwrap();
+            assert_eq!(decompress(&out.0.borrow()), data);
+        }
+    }
 
-        // FIXME:
+    #[test]
+    fn auto_finish() {
+        let out = Shared::default();
+        let mut encoder = Encoder::new(out.clone()).auto_finish();
+        encoder.write_all(b"abc").unwrap();
+        assert!(out.0.borrow().is_empty());
+        drop(encoder);
+        assert_eq!(decompress(&out.0.borrow()), b"abc");
+    }
 
-        let block_header = dec.u24()?;
-        let mut last_block = (block_header & 1) != 0;
-        let mut block_type = match block_header & 0b0110 {
-            0b000 => BlockType::RawBlock,
-            0b010 => BlockType::RleBlock,
-            0b100 => BlockType::ZstdBlock,
-            _ => Err(DecError::InvalidBlockType)?,
-        };
-        if last_block {
-            Err(DecError::NoBlocks)?
+    #[test]
+    fn long_distance_round_trip() {
+        // Two copies of noise, further apart than the default window.
+        let mut data = sample();
+        data.resize(1 << 20, 0);
+        data.extend(noise(3 << 20, 5));
+        data.extend_from_within(1 << 20..);
+
+        let plain = compress(&data, CompressionParams::level(3));
+        let long = compress(&data, CompressionParams::level(3).long_distance(true));
+        assert_eq!(decompress(&long), data);
+        assert!(long.len() < plain.len() * 2 / 3);
+    }
+
+    #[test]
+    fn patch_from() {
+        let old = sample();
+        let mut new = old.clone();
+        for i in (0..new.len()).step_by(50_000) {
+            new[i] ^= 1;
         }
-        let mut block_size = ((block_header >> 3) as usize).min(128_000);
-        let mut buf = &mut self.data[..];
-
-        loop {
-            // Decode this block.
-            match block_type {
-                BlockType::RawBlock => {
-                    // No decompression necessary
-                    dec.bytes(&mut buf[..block_size])?;
-                    buf = &mut buf[block_size..];
-                }
-                BlockType::RleBlock => {
-                    // Run length decompression of a single byte
-                    let single_byte = dec.u8()?;
-                    for i in &mut buf[..block_size] {
-                        *i = single_byte;
-                    }
-                    buf = &mut buf[block_size..];
-                }
-                BlockType::ZstdBlock => {
-                    // ZStandard decompression
-                    
-                    //////////// Literals section //////////
-                    
-                    // Literals Section header
-                    let first_nibble = dec.u(4, 0)?;
-                    let literal_type = match first_nibble & 0b0011 {
-                        0b00 => LiteralType::Raw,
-                        0b01 => LiteralType::Rle,
-                        0b10 => LiteralType::HuffmanTree,
-                        0b11 => LiteralType::HuffmanTreeless,
-                        _ => unreachable!(),
-                    };
-                    use LiteralType::*;
-                    let (regenerated_size, compressed_size, four_huffman_streams) = match literal_type {
-                        Rle | Raw => {
-                            // Size format uses 1 or 2 bits.
-                            let rs = match first_nibble & 0b1100 {
-                                // 1 Bit (Regenerated Size: u5)
-                                0b0000 | 0b1000 => dec.u(5, 5)?,
-                                // 2 Bit (Regenerated Size: u12)
-                                0b0100 => dec.u(12, 4)?,
-                                // 2 Bit (Regenerated Size: u20)
-                                0b1100 => dec.u(20, 4)?,
-
-                                _ => unreachable!(),
-                            };
-                            (rs, None, false)
-                        }
-                        HuffmanTree | HuffmanTreeless => {
-                            // Size format always uses 2 bits.
-                            let (rs, cs, fh) = match first_nibble & 0b1100 {
-                                // 3 Byte Header
-                                // Single Stream: Regenerated Size: u10
-                                0b0000 => (dec.u(10, 4)?, dec.u(10, 2)?, false),
-                                // 4 Streams: Regenerated Size: u10
-                                0b0100 => (dec.u(10, 4)?, dec.u(10, 2)?, true),
-
-                                // 4 Byte Header
-                                // 4 Streams: Regenerated Size: u14
-                                0b1000 => (dec.u(14, 4)?, dec.u(14, 6)?, true),
-
-                                // 5 Byte Header
-                                // 4 Streams: Regenerated Size: u18
-                                0b1100 => (dec.u(18, 4)?, dec.u(18, 2)?, true),
-
-                                _ => unreachable!(),
-                            };
-                            (rs, Some(cs), fh)
-                        }
-                    };
-                    
-                    // Huffman tree description (compressed size include this).
-                    let tree = if literal_type == LiteralType::HuffmanTree {
-                        // FIXME: Use HuffmanTreeBuilder (Next thing to do).
-                        let mut tree = Vec::new();
-                    
-                        let header_byte = dec.u8()?;
-                        if header_byte >= 128 { // List of 4 bit weights
-                            // 0 upto 128 are possible literal values.
-                            let num_weights = header_byte - 127;
-                            tree.reserve_exact(num_weights.into());
-                            for _ in 0..num_weights >> 1 {
-                                let byte = dec.u8()?;
-                                tree.push(byte >> 4);
-                                tree.push(byte & 0xf);
-                            }
-                            if num_weights & 1 != 0 {
-                                let byte = dec.u8()?;
-                                tree.push(byte >> 4);
-                            }
-                        } else { // List of FSE-compressed weights
-                            let compressed_size = header_byte;
-                            tree.reserve_exact(255);
-                            
-                            // decompress using finite state entropy bitstream
-                            // FSE HEADER
-                            let first_byte = dec.u8()?;
-                            let accuracy_log = (first_byte & 0xF) + 5;
-                            
-                            todo!()
-                        }
-                    
-                        Some(tree)
-                    } else {
-                        None
-                    };
-
-                    // Jump Table: compressed sizes of first three streams
-                    // (compressed size include this as well).
-                    let jump_table = if four_huffman_streams {
-                        Some([dec.u16()?, dec.u16()?, dec.u16()?])
-                    } else {
-                        None
-                    };
-
-                    // Decode Streams
-                    if let Some(compressed_size) = compressed_size {
-                        let mut streams = vec![0; compressed_size as usize];
-                        dec.bytes(&mut streams)?;
-                        if let Some(jump_table) = jump_table {
-                            huffman_stream(&streams[..jump_table[0].into()]);
-                            huffman_stream(&streams[..jump_table[1].into()]);
-                            huffman_stream(&streams[..jump_table[2].into()]);
-                            huffman_stream(&streams[..]);
-                        } else {
-                            huffman_stream(&streams[..]);
-                        }
-                    }
-                    
-                    //////////// Sequences section //////////
+        new.splice(200_000..200_500, sample()[..1000].iter().rev().cloned());
+        let plain = compress(&new, CompressionParams::level(1));
+
+        for &pledged in &[false, true] {
+            let mut encoder = Encoder::new(Vec::new()).level(1).ref_prefix(&old);
+            if pledged {
+                encoder = encoder.pledged_size(new.len() as u64);
+            }
+            encoder.write_all(&new).unwrap();
+            let delta = encoder.finish().unwrap();
+            assert!(delta.len() < plain.len() / 20);
+
+            let mut out = Vec::new();
+            Decoder::new(&delta[..]).ref_prefix(&old).read_to_end(&mut out).unwrap();
+            assert!(out == new);
+            assert!(Decoder::new(&delta[..]).read_to_end(&mut Vec::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn patch_from_a_larger_prefix() {
+        // The window only covers the content, so the decoder has to keep
+        // the whole prefix.
+        let old = noise(1 << 20, 5);
+        let new = &old[900_000..910_000];
+        for &pledged in &[false, true] {
+            let mut encoder = Encoder::new(Vec::new()).level(3).ref_prefix(&old);
+            if pledged {
+                encoder = encoder.pledged_size(new.len() as u64);
+            }
+            encoder.write_all(new).unwrap();
+            let delta = encoder.finish().unwrap();
+            assert!(delta.len() < 100);
+
+            let mut out = Vec::new();
+            Decoder::new(&delta[..]).ref_prefix(&old).read_to_end(&mut out).unwrap();
+            assert!(out == new);
+        }
+    }
+
+    #[test]
+    fn block_split() {
+        // Text, then numbers in a different format, within each block.
+        let mut data = Vec::new();
+        for i in 0..20_000u32 {
+            data.extend_from_slice(format!("{} {} {};", i % 251, i % 17, i % 1000).as_bytes());
+            if i % 5000 == 4999 {
+                let numbers = (i..i + 8000).map(|j| format!("{:X}", j.wrapping_mul(2_654_435_761)));
+                data.extend(numbers.flat_map(String::into_bytes));
+            }
+        }
+        for &level in &[5, 19] {
+            let split = compress(&data, CompressionParams::level(level));
+            let whole = compress(&data, CompressionParams::level(level).block_split(false));
+            assert_eq!(decompress(&split), data);
+            assert!(split.len() < whole.len());
+        }
+    }
 
-                    todo!();
+    #[test]
+    fn incompressible() {
+        // Noise, then text.
+        let mut data = noise(1 << 20, 1);
+        data.extend_from_slice(&sample()[..300_000]);
+        for &workers in &[0, 2] {
+            let mut encoder = Encoder::new(Vec::new()).workers(workers);
+            encoder.write_all(&data).unwrap();
+            encoder.flush().unwrap();
+            let stats = encoder.stats();
+            assert_eq!(stats.incompressible_blocks, 8);
+            assert!(stats.raw_blocks >= 8 && stats.compressed_blocks >= 2);
+            assert_eq!(stats.bytes_in, data.len() as u64);
+            let frame = encoder.finish().unwrap();
+            assert!(frame.len() < data.len() - 200_000);
+            assert_eq!(decompress(&frame), data);
+        }
+    }
 
-                    //////////// **Sequence Execution** //////////// 
-                    // Now that we've calculated the literals and sequences, we
-                    // can turn it into data.
+    #[test]
+    fn holds_back_at_most_a_block() {
+        // Input past the first block is compressed as it comes, even with a
+        // large window.
+        let data = sample();
+        let mut encoder = Encoder::new(Vec::new()).level(19);
+        encoder.write_all(&data[..1000]).unwrap();
+        assert_eq!(encoder.stats().bytes_in, 0);
+        encoder.write_all(&data[1000..]).unwrap();
+        assert!(encoder.stats().bytes_in >= data.len() as u64 - (128 << 10));
+        assert_eq!(decompress(&encoder.finish().unwrap()), data);
+    }
 
-                    todo!();
+    #[test]
+    fn target_block_size() {
+        let data = sample();
+        for &level in &[1, 5, 19] {
+            let mut frame = Vec::new();
+            let mut encoder = Encoder::new(&mut frame)
+                .params(CompressionParams::level(level).target_block_size(1400))
+                .pledged_size(data.len() as u64);
+            encoder.write_all(&data).unwrap();
+            drop(encoder);
+            assert_eq!(decompress(&frame), data);
+            // Walk the blocks after the frame header, which has a 4 byte
+            // content size and no window descriptor.
+            assert_eq!(frame[4], 0b1010_0100);
+            let (mut pos, mut blocks) = (9, 0);
+            loop {
+                let header = u32::from_le_bytes([frame[pos], frame[pos + 1], frame[pos + 2], 0]);
+                let size = if (header >> 1) & 3 == 1 { 1 } else { header as usize >> 3 };
+                assert!(size + 3 <= 1400);
+                pos += 3 + size;
+                blocks += 1;
+                if header & 1 == 1 {
+                    break;
                 }
             }
+            assert_eq!(frame.len(), pos + 4);
+            assert!(blocks > frame.len() / 1400);
+            assert!(frame.len() < data.len() / 2);
+        }
+    }
+
+    #[test]
+    fn fit_output() {
+        let data = sample();
+        for &(level, size) in &[(1, 4096), (3, 65_536), (19, 10_000), (3, 200)] {
+            let mut page = vec![0; size];
+            let params = CompressionParams::level(level);
+            let (consumed, written) = compress_to_fit(&data, &mut page, &params).unwrap();
+            assert!(written <= size && written + 16 > size);
+            assert!(consumed > size);
+            assert_eq!(decompress(&page[..written]), &data[..consumed]);
+        }
 
-            // Check if there are more blocks
-            if last_block {
-                break;
+        // Everything fits.
+        let mut page = vec![0; 1000];
+        let (consumed, written) = compress_to_fit(b"abc", &mut page, &Default::default()).unwrap();
+        assert_eq!(consumed, 3);
+        assert_eq!(decompress(&page[..written]), b"abc");
+        let (consumed, written) = compress_to_fit(&[], &mut page, &Default::default()).unwrap();
+        assert_eq!((consumed, written), (0, 13));
+
+        // Room is kept for the header the whole input would need, so 16
+        // bytes are just enough for an empty frame, and one more for a byte.
+        assert!(compress_to_fit(&data, &mut page[..15], &Default::default()).is_err());
+        let (consumed, written) =
+            compress_to_fit(&data, &mut page[..16], &Default::default()).unwrap();
+        assert_eq!((consumed, written), (0, 13));
+        assert!(decompress(&page[..written]).is_empty());
+        let (consumed, written) =
+            compress_to_fit(&data, &mut page[..17], &Default::default()).unwrap();
+        assert_eq!(consumed, 1);
+        assert_eq!(decompress(&page[..written]), &data[..1]);
+    }
+
+    #[test]
+    fn rotate() {
+        let data = sample().repeat(4);
+        for &workers in &[0, 1] {
+            let frames = Rc::new(RefCell::new(Vec::new()));
+            let ranges = frames.clone();
+            // 16 KiB blocks, for frames of a few blocks each, or of a job.
+            let params = CompressionParams::level(3).window_log(14).job_size(1 << 18);
+            let mut encoder = Encoder::new(Vec::new())
+                .params(params)
+                .workers(workers)
+                .rotate_size(10_000)
+                .on_frame(move |range| ranges.borrow_mut().push(range));
+            encoder.write_all(&data).unwrap();
+            let out = encoder.finish().unwrap();
+
+            // Each frame follows the last and decodes on its own.
+            let frames = frames.borrow();
+            assert!(frames.len() > 1);
+            assert_eq!(frames.last().unwrap().end, out.len() as u64);
+            let mut decoded = Vec::new();
+            let mut start = 0;
+            for range in frames.iter() {
+                assert_eq!(range.start, start);
+                let frame = &out[range.start as usize..range.end as usize];
+                decoded.extend_from_slice(&decompress(frame));
+                start = range.end;
+            }
+            assert_eq!(decoded, data);
+            if workers == 0 {
+                assert!(frames.len() > 5);
+                assert!(frames.iter().all(|range| range.end - range.start < 10_000 + (1 << 14)));
             }
-            let block_header = dec.u24()?;
-            last_block = (block_header & 1) != 0;
-            block_type = match block_header & 0b0110 {
-                0b000 => BlockType::RawBlock,
-                0b010 => BlockType::RleBlock,
-                0b100 => BlockType::ZstdBlock,
-                _ => Err(DecError::InvalidBlockType)?,
-            };
-            block_size = ((block_header >> 3) as usize).min(128_000);
         }
 
-        ///////////////////// Content_Checksum ////////////////////
+        // Every write ends a frame.
+        let mut encoder = Encoder::new(Vec::new()).rotate_interval(Duration::ZERO);
+        for chunk in data.chunks(1000).take(5) {
+            encoder.write_all(chunk).unwrap();
+        }
+        let out = encoder.finish().unwrap();
+        assert_eq!(decompress(&out), &data[..5000]);
+        assert_eq!(out.windows(4).filter(|bytes| bytes == &[0x28, 0xB5, 0x2F, 0xFD]).count(), 5);
+    }
 
-        // FIXME:
+    #[test]
+    fn checksum() {
+        let data = sample();
+        let frames = compress(&data, CompressionParams::level(1));
+        let unchecked = compress(&data, CompressionParams::level(1).checksum(false));
+        assert_eq!(frames.len(), unchecked.len() + 4);
+        assert_eq!(frames[4] & 0b100, 0b100);
+        assert_eq!(decompress(&unchecked), data);
+
+        let mut corrupted = frames.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let mut out = Vec::new();
+        assert!(Decoder::new(&corrupted[..]).read_to_end(&mut out).is_err());
+    }
 
-        Ok(())
+    #[test]
+    fn window_limit() {
+        let data = sample();
+        let frames = compress(&data, CompressionParams::level(3));
+        let mut out = Vec::new();
+        assert!(Decoder::new(&frames[..]).window_log_max(16).read_to_end(&mut out).is_err());
     }
-}
 
-#[cfg(test)]
-mod tests {
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn pledged_size() {
+        // More data than the window, so the history has to slide.
+        let data = sample();
+        for &level in &[-5, 1, 3, 5, 13, 16, 19] {
+            let mut frame = Vec::new();
+            let params = CompressionParams::level(level).window_log(16);
+            let mut encoder =
+                Encoder::new(&mut frame).params(params).pledged_size(data.len() as u64);
+            for chunk in data.chunks(10_000) {
+                encoder.write_all(chunk).unwrap();
+            }
+            encoder.finish().unwrap();
+            // One frame with a 4 byte content size.
+            assert_eq!(frame[4], 0b1000_0100);
+            assert_eq!(frame[6..10], (data.len() as u32).to_le_bytes());
+            assert!(frame.len() < data.len() / 2);
+            assert_eq!(decompress(&frame), data);
+        }
+    }
+
+    #[test]
+    fn pledged_single_segment() {
+        let mut frame = Vec::new();
+        let mut encoder = Encoder::new(&mut frame).pledged_size(100);
+        encoder.write_all(&[b'a'; 100]).unwrap();
+        drop(encoder);
+        // Single segment, a 1 byte content size and no window descriptor.
+        assert_eq!(frame[4..6], [0b0010_0100, 100]);
+        assert_eq!(decompress(&frame), [b'a'; 100]);
+
+        let empty = Encoder::new(Vec::new()).pledged_size(0).finish().unwrap();
+        assert_eq!(empty[4..6], [0b0010_0100, 0]);
+        assert!(decompress(&empty).is_empty());
+    }
+
+    #[test]
+    fn pledged_size_mismatch() {
+        let mut encoder = Encoder::new(Vec::new()).pledged_size(10);
+        assert!(encoder.write_all(&[0; 11]).is_err());
+        encoder.write_all(&[0; 6]).unwrap();
+        encoder.flush().unwrap();
+        assert!(encoder.finish().is_err());
+
+        let mut encoder = Encoder::new(Vec::new()).pledged_size(10);
+        encoder.write_all(&[0; 10]).unwrap();
+        assert!(encoder.write_all(&[0]).is_err());
+        assert_eq!(decompress(&encoder.finish().unwrap()), [0; 10]);
     }
 }
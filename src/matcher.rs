@@ -0,0 +1,133 @@
+//! Match finding: turning blocks of data into sequences.
+
+use std::convert::TryInto;
+
+use super::double_fast::DoubleFastMatcher;
+use super::fast::FastMatcher;
+use super::hash_chain::HashChainMatcher;
+use super::ldm::LongDistanceMatcher;
+use super::optimal::OptimalMatcher;
+use super::params::{CompressionParams, Strategy};
+use super::row::RowMatcher;
+use super::sequence::Sequence;
+
+/// Number of bytes read when hashing a position.
+pub(crate) const HASH_READ_SIZE: usize = 8;
+
+/// Finds sequences for the blocks of a frame.
+pub(crate) trait MatchFinder {
+    /// Append the sequences for `data[start..end]` to `sequences`.  Matches
+    /// may reference anything in `data[..end]` within the window; literals
+    /// after the last match are left for the caller.
+    fn find(&mut self, data: &[u8], start: usize, end: usize, sequences: &mut Vec<Sequence>);
+
+    /// Move every position back by `amount`, after the caller drops that
+    /// much from the front of the data.  `amount` is a multiple of the
+    /// chain table size.
+    fn slide(&mut self, amount: usize);
+
+    /// Leave `data[start..end]` out of later searches, after the caller
+    /// stores it without looking for matches in it.
+    fn skip(&mut self, _data: &[u8], _start: usize, _end: usize) {}
+
+    /// Add the positions in `data[start..end]` for later matches to refer
+    /// to, without looking for matches in them.  Much cheaper than `find`
+    /// for history such as a dictionary.
+    fn fill(&mut self, data: &[u8], start: usize, end: usize);
+}
+
+/// Create the match finder for a set of parameters.
+pub(crate) fn new(params: &CompressionParams) -> Box<dyn MatchFinder> {
+    let finder: Box<dyn MatchFinder> = match params.strategy {
+        // Negative levels skip through the data, so one probe is plenty.
+        Strategy::Fast if params.target_length > 0 => Box::new(FastMatcher::new(params)),
+        Strategy::Fast => Box::new(RowMatcher::new(params)),
+        Strategy::DFast => Box::new(DoubleFastMatcher::new(params)),
+        Strategy::Greedy => Box::new(HashChainMatcher::new(params, 0)),
+        Strategy::Lazy => Box::new(HashChainMatcher::new(params, 1)),
+        Strategy::Lazy2 => Box::new(HashChainMatcher::new(params, 2)),
+        Strategy::BtLazy2 => Box::new(HashChainMatcher::binary_tree(params)),
+        Strategy::BtOpt | Strategy::BtUltra | Strategy::BtUltra2 => {
+            Box::new(OptimalMatcher::new(params))
+        }
+    };
+    if params.long_distance {
+        Box::new(LongDistanceMatcher::new(params, finder))
+    } else {
+        finder
+    }
+}
+
+/// Move the positions in a table back by `amount`, emptying those that
+/// fall off the front.
+pub(crate) fn slide(table: &mut [u32], amount: usize) {
+    let amount = amount as u32;
+    table.iter_mut().for_each(|pos| *pos = pos.saturating_sub(amount));
+}
+
+/// Read 8 bytes at `pos` as a little endian integer.
+#[inline(always)]
+pub(crate) fn read_u64(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap())
+}
+
+/// Read 4 bytes at `pos` as a little endian integer.
+#[inline(always)]
+pub(crate) fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+/// Hash the `bytes` (3 to 8) bytes at `pos` into `log` bits.
+#[inline(always)]
+pub(crate) fn hash(data: &[u8], pos: usize, bytes: u8, log: u8) -> usize {
+    const PRIME: u64 = 0xCF1B_BCDC_B7A5_6463;
+    let value = read_u64(data, pos) << (64 - 8 * u32::from(bytes));
+    (value.wrapping_mul(PRIME) >> (64 - u32::from(log))) as usize
+}
+
+/// Count how many bytes match at `a` and `b` (where `a < b`), stopping at
+/// `end`.
+#[inline(always)]
+pub(crate) fn match_len(data: &[u8], a: usize, b: usize, end: usize) -> usize {
+    let mut len = 0;
+    while b + len + 8 <= end {
+        let diff = read_u64(data, a + len) ^ read_u64(data, b + len);
+        if diff != 0 {
+            return len + (diff.trailing_zeros() / 8) as usize;
+        }
+        len += 8;
+    }
+    while b + len < end && data[a + len] == data[b + len] {
+        len += 1;
+    }
+    len
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{match_len, Sequence};
+
+    /// Rebuild a block from its sequences, checking they're valid.
+    pub(crate) fn execute(data: &[u8], start: usize, end: usize, sequences: &[Sequence]) -> Vec<u8> {
+        let mut out = data[..start].to_vec();
+        let mut pos = start;
+        for seq in sequences {
+            assert!(seq.match_len >= 3 && seq.offset != 0);
+            out.extend_from_slice(&data[pos..pos + seq.lit_len as usize]);
+            pos += (seq.lit_len + seq.match_len) as usize;
+            for _ in 0..seq.match_len {
+                out.push(out[out.len() - seq.offset as usize]);
+            }
+        }
+        assert!(pos <= end);
+        out.extend_from_slice(&data[pos..end]);
+        out
+    }
+
+    #[test]
+    fn match_length() {
+        let data = b"abcdefghijklmnopabcdefghijklmnoX";
+        assert_eq!(match_len(data, 0, 16, data.len()), 15);
+        assert_eq!(match_len(data, 0, 16, 20), 4);
+    }
+}
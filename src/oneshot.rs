@@ -0,0 +1,124 @@
+//! One-shot compression and decompression of data held in memory, for when
+//! the streaming [`Encoder`] and [`Decoder`] are more than is needed.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/zstd.h (ZSTD_compressBound)
+
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+
+use super::{Decoder, Encoder, FrameHeader, FrameType};
+
+/// Largest window accepted: the output is all in memory anyway.
+const WINDOW_LOG_MAX: u8 = 31;
+/// Most memory set aside ahead from a frame's content size (64 MiB), since
+/// a damaged or hostile header can claim any size.  Beyond that, the
+/// output grows as it's decoded.
+const PREALLOCATE_MAX: usize = 64 << 20;
+
+/// Largest frame [`compress`] can make from `len` bytes, for sizing
+/// buffers.  This is the same bound as zstd's.
+pub fn compress_bound(len: usize) -> usize {
+    let small = if len < 128 << 10 { ((128 << 10) - len) >> 11 } else { 0 };
+    len + (len >> 8) + small
+}
+
+/// Compress `data` as one frame at `level` (see [`Encoder::level`]),
+/// recording its size in the header.
+pub fn compress(data: &[u8], level: i32) -> Vec<u8> {
+    let out = Vec::with_capacity(compress_bound(data.len()));
+    let mut encoder = Encoder::new(out).level(level).pledged_size(data.len() as u64);
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Decompress all of the frames in `data`.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, IoError> {
+    decompress_with_limit(data, usize::MAX)
+}
+
+/// Decompress all of the frames in `data`, failing with
+/// [`InvalidData`](std::io::ErrorKind::InvalidData) once there's more than
+/// `limit` bytes of it.  The first frame's content size, if it's recorded,
+/// sets how much memory is taken up front (up to the limit, and 64 MiB).
+pub fn decompress_with_limit(data: &[u8], limit: usize) -> Result<Vec<u8>, IoError> {
+    let size = match FrameHeader::parse(data) {
+        Ok((header, _)) if header.frame_type == FrameType::Zstandard => header.content_size,
+        _ => None,
+    };
+    let size = size.unwrap_or(0).min(limit as u64).min(PREALLOCATE_MAX as u64) as usize;
+
+    let mut out = Vec::with_capacity(size);
+    let decoder = Decoder::new(data).window_log_max(WINDOW_LOG_MAX);
+    // A byte past the limit is enough to tell it's too much.
+    decoder.take((limit as u64).saturating_add(1)).read_to_end(&mut out)?;
+    if out.len() > limit {
+        return Err(IoError::new(ErrorKind::InvalidData, "decompressed data over the limit"));
+    }
+    Ok(out)
+}
+
+/// Decompress all of the frames in `data` into `out`.  Returns the size of
+/// the content, or fails with [`WriteZero`](std::io::ErrorKind::WriteZero)
+/// if it doesn't fit.
+pub fn decompress_into(data: &[u8], out: &mut [u8]) -> Result<usize, IoError> {
+    let mut decoder = Decoder::new(data).window_log_max(WINDOW_LOG_MAX);
+    let mut len = 0;
+    while len < out.len() {
+        match decoder.read(&mut out[len..])? {
+            0 => return Ok(len),
+            amt => len += amt,
+        }
+    }
+    if decoder.read(&mut [0])? != 0 {
+        return Err(IoError::new(ErrorKind::WriteZero, "output too small for the content"));
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, compress_bound, decompress, decompress_into, decompress_with_limit};
+
+    #[test]
+    fn round_trip() {
+        let text: Vec<u8> =
+            (0..20_000u32).flat_map(|i| format!("{} ", i % 300).into_bytes()).collect();
+        for data in [&b""[..], b"a", &text] {
+            for &level in &[-1, 3, 19] {
+                let frame = compress(data, level);
+                assert!(frame.len() <= compress_bound(data.len()));
+                assert_eq!(decompress(&frame).unwrap(), data);
+
+                let mut out = vec![0; data.len()];
+                assert_eq!(decompress_into(&frame, &mut out).unwrap(), data.len());
+                assert_eq!(out, data);
+            }
+        }
+        let frame = compress(&text, 3);
+        assert!(decompress_into(&frame, &mut vec![0; text.len() - 1]).is_err());
+        assert_eq!(decompress_with_limit(&frame, text.len()).unwrap(), text);
+        assert!(decompress_with_limit(&frame, text.len() - 1).is_err());
+    }
+
+    #[test]
+    fn sequences_cannot_outgrow_the_block() {
+        // A raw block, then a block of 98303 sequences with RLE codes, no
+        // literals and a one byte bitstream.  Long matches need more bits
+        // than the stream holds; short ones need none, but add up to more
+        // than a block.
+        for &ml_code in &[52, 31] {
+            let mut frame = vec![0x28, 0xB5, 0x2F, 0xFD, 0x00, 0x50];
+            frame.extend_from_slice(&[0x40, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+            frame.extend_from_slice(&[0x4D, 0, 0, 0x00, 0xFF, 0xFF, 0xFF, 0x54, 0, 0, ml_code, 1]);
+            assert_eq!(frame.len(), 29);
+            assert!(decompress_with_limit(&frame, 1 << 20).is_err());
+        }
+    }
+
+    #[test]
+    fn incompressible_within_bound() {
+        let noise = crate::tests::noise(300_000, 1);
+        for len in [0, 100, 1000, 130_000, 300_000] {
+            assert!(compress(&noise[..len], 3).len() <= compress_bound(len));
+        }
+    }
+}
@@ -0,0 +1,467 @@
+//! Optimal parser: picks the sequences with the lowest estimated cost in
+//! bits, given every match the binary tree finds.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/compress/zstd_opt.c
+
+use super::binary_tree::BinaryTree;
+use super::matcher::{self, MatchFinder, HASH_READ_SIZE};
+use super::params::{CompressionParams, Strategy};
+use super::sequence::{self, RepeatOffsets, Sequence};
+
+/// Most positions priced before committing to a path.
+const OPT_NUM: usize = 1 << 12;
+/// Fractional bits in a price.
+const PRICE_SHIFT: u32 = 8;
+/// How much each literal adds to its frequency.
+const LITERAL_ADD: u32 = 2;
+
+/// Starting literal length frequencies: short runs are common.
+const LL_START: [u32; 36] = [
+    4, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1,
+];
+/// Starting offset code frequencies: repeats and nearby offsets are common.
+const OF_START: [u32; 32] = [
+    6, 2, 1, 1, 2, 3, 4, 4, 4, 3, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1,
+];
+
+/// Frequencies of one kind of symbol.
+#[derive(Clone, Default)]
+struct Stats {
+    freqs: Vec<u32>,
+    sum: u32,
+}
+
+impl Stats {
+    fn new(freqs: Vec<u32>) -> Self {
+        let sum = freqs.iter().sum();
+        Self { freqs, sum }
+    }
+
+    fn add(&mut self, symbol: usize, amount: u32) {
+        self.freqs[symbol] += amount;
+        self.sum += amount;
+    }
+
+    /// Shrink the frequencies to a total of about `1 << log`, so that newer
+    /// data counts for more.
+    fn scale(&mut self, log: u32) {
+        let factor = self.sum >> log;
+        if factor > 1 {
+            let shift = 31 - factor.leading_zeros();
+            let freqs = self.freqs.iter().map(|&freq| 1 + (freq >> shift)).collect();
+            *self = Self::new(freqs);
+        }
+    }
+}
+
+/// Estimates the cost of literals and sequences from the symbols chosen so
+/// far, in 1/256ths of a bit.
+#[derive(Clone)]
+struct Prices {
+    literals: Stats,
+    lit_lens: Stats,
+    match_lens: Stats,
+    offsets: Stats,
+    /// Use fractional bits rather than whole ones.
+    accurate: bool,
+    seeded: bool,
+}
+
+impl Prices {
+    fn new(accurate: bool) -> Self {
+        Self {
+            literals: Stats::default(),
+            lit_lens: Stats::default(),
+            match_lens: Stats::default(),
+            offsets: Stats::default(),
+            accurate,
+            seeded: false,
+        }
+    }
+
+    /// Get ready for a new block: seed the statistics from its bytes the
+    /// first time, otherwise age the ones gathered so far.
+    fn start_block(&mut self, block: &[u8]) {
+        if self.seeded {
+            self.literals.scale(12);
+            self.lit_lens.scale(11);
+            self.match_lens.scale(11);
+            self.offsets.scale(11);
+            return;
+        }
+        let mut counts = [0u32; 256];
+        for &byte in block {
+            counts[usize::from(byte)] += 1;
+        }
+        self.literals = Stats::new(counts.iter().map(|&count| 1 + (count >> 8)).collect());
+        self.lit_lens = Stats::new(LL_START.to_vec());
+        self.match_lens = Stats::new(vec![1; sequence::ML_CODES.len()]);
+        self.offsets = Stats::new(OF_START.to_vec());
+        self.seeded = true;
+    }
+
+    /// Roughly `log2(freq) << PRICE_SHIFT`.
+    #[inline(always)]
+    fn weight(&self, freq: u32) -> i64 {
+        let freq = u64::from(freq) + 1;
+        let high = 63 - freq.leading_zeros();
+        let whole = i64::from(high) << PRICE_SHIFT;
+        if self.accurate {
+            whole + ((freq << PRICE_SHIFT) >> high) as i64
+        } else {
+            whole
+        }
+    }
+
+    #[inline(always)]
+    fn price(&self, stats: &Stats, symbol: u8) -> i64 {
+        self.weight(stats.sum) - self.weight(stats.freqs[usize::from(symbol)])
+    }
+
+    /// Cost of one literal byte.
+    #[inline(always)]
+    fn literal(&self, byte: u8) -> i64 {
+        // Every literal costs at least a bit.
+        let max = self.weight(self.literals.sum) - (1 << PRICE_SHIFT);
+        self.weight(self.literals.sum) - self.weight(self.literals.freqs[usize::from(byte)]).min(max)
+    }
+
+    /// Cost of coding a literal length.
+    #[inline(always)]
+    fn lit_len(&self, lit_len: usize) -> i64 {
+        let (code, bits, _) = sequence::ll_code(lit_len as u32);
+        (i64::from(bits) << PRICE_SHIFT) + self.price(&self.lit_lens, code)
+    }
+
+    /// Cost of coding a match length.
+    #[inline(always)]
+    fn match_len(&self, match_len: usize) -> i64 {
+        let (code, bits, _) = sequence::ml_code(match_len as u32);
+        (i64::from(bits) << PRICE_SHIFT) + self.price(&self.match_lens, code)
+    }
+
+    /// Cost of coding an offset value.
+    #[inline(always)]
+    fn offset(&self, offset_value: u32) -> i64 {
+        let (code, bits, _) = sequence::of_code(offset_value);
+        (i64::from(bits) << PRICE_SHIFT) + self.price(&self.offsets, code)
+    }
+
+    /// Count a sequence that was chosen.
+    fn update(&mut self, literals: &[u8], offset_value: u32, match_len: usize) {
+        for &byte in literals {
+            self.literals.add(usize::from(byte), LITERAL_ADD);
+        }
+        let ll = sequence::ll_code(literals.len() as u32).0;
+        let ml = sequence::ml_code(match_len as u32).0;
+        let of = sequence::of_code(offset_value).0;
+        self.lit_lens.add(usize::from(ll), 1);
+        self.match_lens.add(usize::from(ml), 1);
+        self.offsets.add(usize::from(of), 1);
+    }
+}
+
+/// Cheapest known way to reach a position.
+#[derive(Copy, Clone)]
+struct Node {
+    /// Cost so far, including the literal length of the trailing run.
+    price: i64,
+    /// Literals since the last match.
+    lit_len: usize,
+    /// Length of the match that ends here, or 0 for a literal.
+    match_len: usize,
+    offset: usize,
+    reps: RepeatOffsets,
+}
+
+impl Node {
+    const UNREACHED: Node = Node {
+        price: i64::MAX,
+        lit_len: 0,
+        match_len: 0,
+        offset: 0,
+        reps: RepeatOffsets([1, 4, 8]),
+    };
+}
+
+/// Binary tree match finder with an optimal parser (btopt, btultra and
+/// btultra2).
+pub(crate) struct OptimalMatcher {
+    tree: BinaryTree,
+    prices: Prices,
+    reps: RepeatOffsets,
+    /// Matches at least this long are taken without pricing alternatives.
+    target_length: usize,
+    /// Parse the first block twice, keeping only the statistics from the
+    /// first pass.
+    two_pass: bool,
+    /// Candidate (length, offset) pairs at the current position.
+    matches: Vec<(usize, usize)>,
+    nodes: Vec<Node>,
+    /// Matches on the chosen path: (position, length, offset).
+    path: Vec<(usize, usize, usize)>,
+}
+
+impl OptimalMatcher {
+    /// Create an optimal parser for one of the binary tree strategies.
+    pub(crate) fn new(params: &CompressionParams) -> Self {
+        Self {
+            tree: BinaryTree::new(params),
+            prices: Prices::new(params.strategy >= Strategy::BtUltra),
+            reps: RepeatOffsets::default(),
+            target_length: (params.target_length as usize).clamp(1, OPT_NUM - 1),
+            two_pass: params.strategy == Strategy::BtUltra2,
+            matches: Vec::new(),
+            nodes: Vec::with_capacity(OPT_NUM + 1),
+            path: Vec::new(),
+        }
+    }
+
+    /// Collect the matches at `ip`, repeat offsets first, each longer than
+    /// the one before.
+    fn candidates(
+        &mut self,
+        data: &[u8],
+        ip: usize,
+        end: usize,
+        lit_len: usize,
+        reps: RepeatOffsets,
+    ) {
+        self.matches.clear();
+        let [rep0, rep1, rep2] = reps.0;
+        let reps = if lit_len == 0 {
+            [rep1, rep2, rep0 - 1]
+        } else {
+            [rep0, rep1, rep2]
+        };
+        let mut best_len = self.tree.min_match() - 1;
+        for &rep in &reps {
+            let rep = rep as usize;
+            if rep == 0 || rep > ip || rep > self.tree.window() {
+                continue;
+            }
+            let len = matcher::match_len(data, ip - rep, ip, end);
+            if len > best_len {
+                best_len = len;
+                self.matches.push((len, rep));
+            }
+        }
+        self.tree.find(data, ip, end, best_len, &mut self.matches);
+    }
+
+    /// Price reaching each position after `cur` with the current matches.
+    fn add_matches(&mut self, cur: usize) {
+        let from = self.nodes[cur];
+        // The literal run after the match starts out empty.
+        let base = from.price + self.prices.lit_len(0);
+        let mut len = self.tree.min_match();
+        for &(match_len, offset) in &self.matches {
+            let mut reps = from.reps;
+            let offset_value = reps.encode(from.lit_len as u32, offset as u32);
+            let offset_price = base + self.prices.offset(offset_value);
+            if cur + match_len >= self.nodes.len() {
+                self.nodes.resize(cur + match_len + 1, Node::UNREACHED);
+            }
+            while len <= match_len {
+                let price = offset_price + self.prices.match_len(len);
+                let node = &mut self.nodes[cur + len];
+                if price < node.price {
+                    *node = Node { price, lit_len: 0, match_len: len, offset, reps };
+                }
+                len += 1;
+            }
+        }
+    }
+
+    /// Emit a sequence and count it in the statistics.
+    fn emit(
+        &mut self,
+        data: &[u8],
+        anchor: usize,
+        pos: usize,
+        match_len: usize,
+        offset: usize,
+        sequences: &mut Vec<Sequence>,
+    ) {
+        let lit_len = (pos - anchor) as u32;
+        let offset_value = self.reps.encode(lit_len, offset as u32);
+        self.prices.update(&data[anchor..pos], offset_value, match_len);
+        sequences.push(Sequence {
+            lit_len,
+            match_len: match_len as u32,
+            offset: offset as u32,
+        });
+    }
+
+    /// Find the cheapest way through the positions from `ip`, starting with
+    /// the matches already found there.  Returns how far the path goes.
+    fn optimize(&mut self, data: &[u8], ip: usize, end: usize, lit_len: usize) -> usize {
+        let limit = end.saturating_sub(HASH_READ_SIZE);
+        self.nodes.clear();
+        let price = self.prices.lit_len(lit_len);
+        self.nodes.push(Node { price, lit_len, reps: self.reps, ..Node::UNREACHED });
+        self.add_matches(0);
+
+        let mut last = self.nodes.len() - 1;
+        let mut cur = 1;
+        while cur <= last {
+            // Arrive with one more literal.
+            let prev = self.nodes[cur - 1];
+            let lit_len = prev.lit_len + 1;
+            let price = prev.price
+                + self.prices.literal(data[ip + cur - 1])
+                + self.prices.lit_len(lit_len)
+                - self.prices.lit_len(lit_len - 1);
+            if price <= self.nodes[cur].price {
+                self.nodes[cur] = Node { price, lit_len, match_len: 0, offset: 0, reps: prev.reps };
+            }
+            if cur == last || ip + cur >= limit {
+                cur += 1;
+                continue;
+            }
+
+            let node = self.nodes[cur];
+            self.candidates(data, ip + cur, end, node.lit_len, node.reps);
+            if let Some(&(len, offset)) = self.matches.last() {
+                if len >= self.target_length || cur + len >= OPT_NUM {
+                    // Long enough: stop here and take it.
+                    let mut reps = node.reps;
+                    reps.encode(node.lit_len as u32, offset as u32);
+                    last = cur + len;
+                    if last >= self.nodes.len() {
+                        self.nodes.resize(last + 1, Node::UNREACHED);
+                    }
+                    self.nodes[last] = Node { price: 0, lit_len: 0, match_len: len, offset, reps };
+                    break;
+                }
+                self.add_matches(cur);
+                last = self.nodes.len() - 1;
+            }
+            cur += 1;
+        }
+        last
+    }
+
+    /// Parse a block into sequences.
+    fn parse(&mut self, data: &[u8], start: usize, end: usize, sequences: &mut Vec<Sequence>) {
+        let limit = end.saturating_sub(HASH_READ_SIZE);
+        let mut anchor = start;
+        let mut ip = start.max(1);
+
+        while ip < limit {
+            self.candidates(data, ip, end, ip - anchor, self.reps);
+            let (len, offset) = match self.matches.last() {
+                Some(&longest) => longest,
+                None => {
+                    ip += 1;
+                    continue;
+                }
+            };
+            if len >= self.target_length {
+                self.emit(data, anchor, ip, len, offset, sequences);
+                ip += len;
+                anchor = ip;
+                continue;
+            }
+
+            let last = self.optimize(data, ip, end, ip - anchor);
+            self.path.clear();
+            let mut pos = last;
+            while pos > 0 {
+                let node = self.nodes[pos];
+                if node.match_len == 0 {
+                    pos -= 1;
+                } else {
+                    pos -= node.match_len;
+                    self.path.push((pos, node.match_len, node.offset));
+                }
+            }
+            for i in (0..self.path.len()).rev() {
+                let (pos, len, offset) = self.path[i];
+                self.emit(data, anchor, ip + pos, len, offset, sequences);
+                anchor = ip + pos + len;
+            }
+            ip += last;
+        }
+    }
+}
+
+impl MatchFinder for OptimalMatcher {
+    fn find(&mut self, data: &[u8], start: usize, end: usize, sequences: &mut Vec<Sequence>) {
+        if self.two_pass && !self.prices.seeded {
+            let mut discard = Vec::new();
+            self.prices.start_block(&data[start..end]);
+            self.parse(data, start, end, &mut discard);
+            self.tree.reset();
+            self.reps = RepeatOffsets::default();
+        }
+        self.prices.start_block(&data[start..end]);
+        self.parse(data, start, end, sequences);
+    }
+
+    fn slide(&mut self, amount: usize) {
+        self.tree.slide(amount);
+    }
+
+    fn skip(&mut self, _data: &[u8], _start: usize, end: usize) {
+        self.tree.skip(end);
+    }
+
+    fn fill(&mut self, data: &[u8], start: usize, end: usize) {
+        self.tree.skip(start);
+        self.tree.fill(data, end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MatchFinder, OptimalMatcher};
+    use crate::block::BlockEncoder;
+    use crate::hash_chain::HashChainMatcher;
+    use crate::matcher::tests::execute;
+    use crate::params::CompressionParams;
+    use crate::tests::random;
+
+    #[test]
+    fn sequences_rebuild_input() {
+        let mut data = Vec::new();
+        for i in 0..20_000u32 {
+            data.extend_from_slice(format!("{} {} {};", i % 251, i % 17, i % 1000).as_bytes());
+        }
+        let middle = data.len() / 3;
+        for &level in &[16, 18, 19] {
+            let params = CompressionParams::level(level).adjust(data.len() as u64);
+            let mut matcher = OptimalMatcher::new(&params);
+            let mut sequences = Vec::new();
+            matcher.find(&data, 0, middle, &mut sequences);
+            assert_eq!(execute(&data, 0, middle, &sequences), &data[..middle]);
+            let mut more = Vec::new();
+            matcher.find(&data, middle, data.len(), &mut more);
+            assert_eq!(execute(&data, middle, data.len(), &more), data);
+        }
+    }
+
+    #[test]
+    fn smaller_than_lazy() {
+        // Words picked by a pseudo-random generator, so matches are short
+        // and there are choices to make.
+        let words = ["the ", "quick ", "brown ", "fox ", "jumps ", "over ", "a ", "lazy ", "dog. "];
+        let mut random = random(1);
+        let mut data = Vec::new();
+        while data.len() < 60_000 {
+            let state = random.next().unwrap();
+            data.extend_from_slice(words[(state >> 16) as usize % words.len()].as_bytes());
+        }
+        let params = CompressionParams::level(19).adjust(data.len() as u64);
+        let size = |matcher: &mut dyn MatchFinder| {
+            let mut sequences = Vec::new();
+            matcher.find(&data, 0, data.len(), &mut sequences);
+            BlockEncoder::new().compress(&data, &sequences).unwrap().len()
+        };
+        let optimal = size(&mut OptimalMatcher::new(&params));
+        let lazy = size(&mut HashChainMatcher::new(&params, 2));
+        assert!(optimal <= lazy, "{} > {}", optimal, lazy);
+    }
+}
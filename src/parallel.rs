@@ -0,0 +1,342 @@
+//! Multi-threaded compression: the input of a frame is split into jobs that
+//! worker threads compress at the same time, each seeing some of the data
+//! before it.
+
+use std::collections::VecDeque;
+use std::io::{Error as IoError, Write};
+use std::mem;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use super::adapt::Adapt;
+use super::block::BlockEncoder;
+use super::dictionary::Dictionary;
+use super::encoder::{self, EncoderStats, FrameEncoder};
+use super::ldm::GEAR;
+use super::xxhash::Xxh64;
+use super::{write_block_header, BlockType, CompressionParams};
+
+/// Least input in a job before a sync point may end it.
+const RSYNC_MIN_JOB: usize = 1 << 17;
+
+/// Input for a worker to compress into blocks.
+struct Job {
+    params: CompressionParams,
+    /// Data before the input that matches may refer to, then the input.
+    data: Vec<u8>,
+    history: usize,
+    /// The job starts the frame, from the dictionary if there is one.
+    first: Option<Option<Dictionary>>,
+    last: bool,
+    output: Sender<(Vec<u8>, EncoderStats)>,
+}
+
+impl Job {
+    fn run(self) {
+        let (history, input) = self.data.split_at(self.history);
+        let blocks = match &self.first {
+            Some(Some(dictionary)) => BlockEncoder::with_dictionary(dictionary),
+            Some(None) => BlockEncoder::new(),
+            None => BlockEncoder::resumed(),
+        };
+        let mut frame = FrameEncoder::resume(&self.params, history, blocks, false);
+        let mut out = Vec::new();
+        // Writing to a `Vec` can't fail.
+        frame.write(&mut out, input).unwrap();
+        frame.compress_pending(&mut out, self.last).unwrap();
+        // Nobody is waiting if the frame was dropped.
+        let _ = self.output.send((out, frame.stats));
+    }
+}
+
+/// Compresses one frame with a pool of worker threads.  Jobs are cut at
+/// fixed sizes, or at sync points in rsyncable mode, and see a fixed amount
+/// of history, so the output doesn't depend on the number of workers.
+pub(crate) struct ParallelEncoder {
+    params: CompressionParams,
+    /// Only used for the first job.
+    dictionary: Option<Dictionary>,
+    hasher: Option<Xxh64>,
+    /// History for the next job, then its input so far.
+    buffer: Vec<u8>,
+    history: usize,
+    first: bool,
+    /// In rsyncable mode, the bits of the rolling hash that are all clear
+    /// at a sync point.
+    sync_mask: Option<u64>,
+    /// Gear hash of the input so far.
+    gear: u64,
+    /// The job in the buffer ended at a sync point.
+    synced: bool,
+    /// Compressed blocks of the jobs in progress, in order.
+    outputs: VecDeque<Receiver<(Vec<u8>, EncoderStats)>>,
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    /// Changes the level between jobs, if set.
+    pub(crate) adapt: Option<Adapt>,
+    /// Counts for the jobs written so far.
+    pub(crate) stats: EncoderStats,
+}
+
+impl ParallelEncoder {
+    /// Write the frame header and start `workers` threads.
+    pub(crate) fn new<W: Write>(
+        writer: &mut W,
+        params: &CompressionParams,
+        content_size: Option<u64>,
+        dictionary: Option<&Dictionary>,
+        workers: usize,
+    ) -> Result<Self, IoError> {
+        let params = encoder::write_header(writer, params, content_size, dictionary)?;
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    job.run();
+                })
+            })
+            .collect();
+        let buffer = dictionary.map_or(Vec::new(), |dictionary| dictionary.content().to_vec());
+        // Sync points come about once per job on random data.
+        let sync_bits = 63 - (params.job() as u64).leading_zeros();
+        Ok(Self {
+            params,
+            dictionary: dictionary.cloned(),
+            hasher: if params.checksum { Some(Xxh64::new()) } else { None },
+            history: buffer.len(),
+            buffer,
+            first: true,
+            sync_mask: if params.rsyncable { Some(!0 << (64 - sync_bits)) } else { None },
+            gear: 0,
+            synced: false,
+            outputs: VecDeque::new(),
+            jobs: Some(jobs),
+            workers,
+            adapt: None,
+            stats: EncoderStats::default(),
+        })
+    }
+
+    /// Add content, handing out every full job except the last.  In
+    /// rsyncable mode, a job also ends after a byte where the rolling hash
+    /// has the bits of the mask clear.
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        writer: &mut W,
+        mut buf: &[u8],
+    ) -> Result<(), IoError> {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(buf);
+        }
+        let job = self.params.job();
+        while !buf.is_empty() {
+            if self.buffer.len() - self.history == job || self.synced {
+                self.submit(writer, false)?;
+            }
+            let filled = self.buffer.len() - self.history;
+            let mut amt = (job - filled).min(buf.len());
+            if let Some(mask) = self.sync_mask {
+                for (i, &byte) in buf[..amt].iter().enumerate() {
+                    self.gear = (self.gear << 1).wrapping_add(GEAR[usize::from(byte)]);
+                    if self.gear & mask == 0 && filled + i + 1 >= RSYNC_MIN_JOB {
+                        amt = i + 1;
+                        self.synced = true;
+                        break;
+                    }
+                }
+            }
+            self.buffer.extend_from_slice(&buf[..amt]);
+            buf = &buf[amt..];
+        }
+        Ok(())
+    }
+
+    /// Compress what's left as the last job, wait for all of them, then
+    /// write the checksum.
+    pub(crate) fn finish<W: Write>(mut self, writer: &mut W) -> Result<EncoderStats, IoError> {
+        let pending = self.buffer.len() > self.history;
+        if pending {
+            self.submit(writer, true)?;
+        }
+        while !self.outputs.is_empty() {
+            self.write_next(writer)?;
+        }
+        if !pending {
+            write_block_header(writer, true, BlockType::Raw, 0)?;
+            self.stats.add_block(BlockType::Raw, 0, 0);
+        }
+
+        ///////////////////// Content_Checksum ////////////////////
+
+        if let Some(hasher) = &self.hasher {
+            writer.write_all(&(hasher.digest() as u32).to_le_bytes())?;
+        }
+        Ok(self.stats)
+    }
+
+    /// Compress the buffered input as a job of its own, then wait for all
+    /// of the jobs, without ending the frame.
+    pub(crate) fn flush<W: Write>(&mut self, writer: &mut W) -> Result<(), IoError> {
+        if self.buffer.len() > self.history {
+            self.submit(writer, false)?;
+        }
+        while !self.outputs.is_empty() {
+            self.write_next(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Hand the buffered input to a worker, keeping the end of it as the
+    /// next job's history.
+    fn submit<W: Write>(&mut self, writer: &mut W, last: bool) -> Result<(), IoError> {
+        let overlap = self.params.overlap().min(self.buffer.len());
+        let next = self.buffer[self.buffer.len() - overlap..].to_vec();
+        let (output, receiver) = mpsc::channel();
+        let first = if self.first { Some(self.dictionary.take()) } else { None };
+        let job = Job {
+            params: self.params,
+            data: mem::replace(&mut self.buffer, next),
+            history: mem::replace(&mut self.history, overlap),
+            first,
+            last,
+            output,
+        };
+        self.first = false;
+        self.synced = false;
+        self.jobs.as_ref().unwrap().send(job).map_err(|_| worker_error())?;
+        self.outputs.push_back(receiver);
+
+        // Limit the memory held by jobs waiting to be written.
+        while self.outputs.len() > self.workers.len() * 2 {
+            self.write_next(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the oldest job and write its blocks.  If the level adapts,
+    /// the wait counts as time spent compressing.
+    fn write_next<W: Write>(&mut self, writer: &mut W) -> Result<(), IoError> {
+        let receiver = self.outputs.pop_front().unwrap();
+        let started = Instant::now();
+        let (blocks, stats) = receiver.recv().map_err(|_| worker_error())?;
+        self.stats.add(&stats);
+        let writing = Instant::now();
+        writer.write_all(&blocks)?;
+        if let Some(adapt) = &mut self.adapt {
+            let count = stats.compressed_blocks + stats.raw_blocks + stats.rle_blocks;
+            if let Some(level) = adapt.record(count, started.elapsed(), writing.elapsed()) {
+                self.params = self.params.at_level(level);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ParallelEncoder {
+    fn drop(&mut self) {
+        // Closing the channel stops the workers once they're done.
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker_error() -> IoError {
+    IoError::other("a compression worker thread panicked")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{compress_with_workers, decompress, noise, random, sample};
+    use crate::{CompressionParams, Dictionary, Encoder};
+    use std::io::Write;
+
+    /// The sample, then noise that repeats two jobs later.
+    fn repeating() -> Vec<u8> {
+        let mut data = sample();
+        let start = data.len();
+        data.extend(noise(1 << 19, 3));
+        data.extend_from_within(start..);
+        data
+    }
+
+    #[test]
+    fn same_output_for_any_workers() {
+        let data = repeating();
+        for &level in &[1, 5, 13] {
+            let params = CompressionParams::level(level).job_size(1 << 18);
+            let frame = compress_with_workers(&data, params, 1);
+            assert_eq!(decompress(&frame), data);
+            for &workers in &[2, 5] {
+                assert!(compress_with_workers(&data, params, workers) == frame);
+            }
+        }
+    }
+
+    #[test]
+    fn overlap_helps() {
+        let data = repeating();
+        let params = CompressionParams::level(3).job_size(1 << 18);
+        let without = compress_with_workers(&data, params.overlap_log(0), 4);
+        let with = compress_with_workers(&data, params.overlap_log(9), 4);
+        assert_eq!(decompress(&without), data);
+        assert_eq!(decompress(&with), data);
+        assert!(with.len() < without.len() - (200 << 10));
+    }
+
+    #[test]
+    fn rsyncable_resynchronizes() {
+        // Lines that don't repeat, and the same with a byte inserted.
+        let mut data = Vec::new();
+        let mut random = random(5);
+        while data.len() < 2 << 20 {
+            let state = random.next().unwrap();
+            data.extend_from_slice(format!("{:08x} {}\n", state, state % 1000).as_bytes());
+        }
+        let mut edited = data.clone();
+        edited.insert(1000, b'!');
+
+        // Bytes at the end of the second frame that are the same in the first.
+        let common = |params: CompressionParams| {
+            let before = compress_with_workers(&data, params, 2);
+            let after = compress_with_workers(&edited, params, 2);
+            assert_eq!(decompress(&after), edited);
+            let same = before.iter().rev().zip(after.iter().rev()).take_while(|(a, b)| a == b);
+            (same.count(), after.len())
+        };
+        let params = CompressionParams::level(3).job_size(1 << 18).checksum(false);
+        let (same, len) = common(params.rsyncable(true));
+        assert!(same > len / 2);
+        let (same, _) = common(params);
+        assert!(same < 1000);
+    }
+
+    #[test]
+    fn pledged_with_dictionary() {
+        let data = sample();
+        let dictionary = Dictionary::new(&data[..1000]).unwrap();
+        let mut frame = Vec::new();
+        let params = CompressionParams::level(3).job_size(1 << 18);
+        let mut encoder = Encoder::new(&mut frame)
+            .params(params)
+            .with_dictionary(&dictionary)
+            .pledged_size(data.len() as u64)
+            .workers(2);
+        encoder.write_all(&data).unwrap();
+        drop(encoder);
+
+        let mut out = Vec::new();
+        let mut decoder = crate::Decoder::new(&frame[..]).with_dictionary(&dictionary);
+        std::io::Read::read_to_end(&mut decoder, &mut out).unwrap();
+        assert!(out == data);
+    }
+}
@@ -0,0 +1,502 @@
+//! Compression levels and the parameters they map to.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/compress/clevels.h
+
+/// Lowest (fastest) compression level.
+pub(crate) const MIN_LEVEL: i32 = -(1 << 17);
+/// Highest (strongest) compression level.
+pub(crate) const MAX_LEVEL: i32 = 22;
+/// Level used when none is specified, or when level 0 is requested.
+pub(crate) const DEFAULT_LEVEL: i32 = 3;
+
+/// Smallest window log that can be written in a frame header.
+pub(crate) const WINDOW_LOG_MIN: u8 = 10;
+/// Largest window log supported by this encoder (2 GiB).
+pub(crate) const WINDOW_LOG_MAX: u8 = 31;
+/// Smallest window used with long distance matching (128 MiB).
+pub(crate) const LDM_WINDOW_LOG: u8 = 27;
+/// Largest block size allowed by the format (128 KiB).
+pub(crate) const BLOCK_SIZE_MAX: usize = 1 << 17;
+/// Smallest target compressed block size, as in zstd.
+const TARGET_BLOCK_SIZE_MIN: usize = 1340;
+
+/// The match finding strategy used by the encoder, from fastest to
+/// strongest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Strategy {
+    /// Rows of recent positions per hash, checking the newest one with the
+    /// same tag (a single hash table for the negative levels).
+    Fast,
+    /// Two hash tables (short and long matches).
+    DFast,
+    /// Hash chain, take the first match found.
+    Greedy,
+    /// Hash chain, check one position ahead for a better match.
+    Lazy,
+    /// Hash chain, check two positions ahead for a better match.
+    Lazy2,
+    /// Binary tree, check two positions ahead for a better match.
+    BtLazy2,
+    /// Binary tree with an optimal parser.
+    BtOpt,
+    /// Binary tree with an optimal parser that updates its prices more
+    /// often.
+    BtUltra,
+    /// Like `BtUltra`, but takes a second pass over the first block to
+    /// seed the price statistics.
+    BtUltra2,
+}
+
+/// Parameters that control how data is compressed, starting from a
+/// compression level.  Values outside of the bounds allowed by the format
+/// are clamped.
+///
+/// ```rust
+/// use zstandard::{CompressionParams, Strategy};
+///
+/// let params = CompressionParams::level(19)
+///     .window_size(3 << 20)
+///     .strategy(Strategy::Lazy2)
+///     .checksum(false);
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CompressionParams {
+    /// The level the parameters came from, which an adaptive level starts
+    /// from.
+    pub(crate) level: i32,
+    /// Log2 of the largest back-reference distance.
+    pub(crate) window_log: u8,
+    /// Window size in eighths of `1 << window_log` added on top of it.
+    pub(crate) window_mantissa: u8,
+    /// Log2 of the chain (or binary tree) table size.
+    pub(crate) chain_log: u8,
+    /// Log2 of the hash table size.
+    pub(crate) hash_log: u8,
+    /// Log2 of the number of searches per position.
+    pub(crate) search_log: u8,
+    /// Shortest match searched for.
+    pub(crate) min_match: u8,
+    /// Meaning depends on the strategy: the acceleration for `Fast`, and
+    /// the length at which the optimal parser stops searching.
+    pub(crate) target_length: u32,
+    /// Match finding strategy.
+    pub(crate) strategy: Strategy,
+    /// Whether to append a checksum of the content to each frame.
+    pub(crate) checksum: bool,
+    /// Whether to write the content size in frame headers when known.
+    pub(crate) content_size: bool,
+    /// Whether to write the dictionary ID in frame headers.
+    pub(crate) dictionary_id: bool,
+    /// Whether to look for long matches across the whole window.
+    pub(crate) long_distance: bool,
+    /// Input per job when compressing with several workers, or 0 to pick
+    /// one from the window size.
+    pub(crate) job_size: usize,
+    /// How much of the window each job sees before its start: none for 0,
+    /// otherwise `window >> (9 - overlap_log)`.
+    pub(crate) overlap_log: u8,
+    /// Whether to split blocks where the data's statistics change.
+    pub(crate) block_split: bool,
+    /// Whether to end jobs where the content calls for it, so unchanged
+    /// data compresses the same.
+    pub(crate) rsyncable: bool,
+    /// Compressed size to end blocks at, or 0 to leave them at the full
+    /// block size.
+    pub(crate) target_block_size: usize,
+}
+
+/// Window, chain, hash, search, min match, target length and strategy for
+/// each level, starting at the base for negative levels.
+const LEVELS: [(u8, u8, u8, u8, u8, u32, Strategy); 23] = {
+    use Strategy::*;
+    [
+        (19, 12, 13, 1, 6, 1, Fast),
+        (19, 13, 14, 1, 7, 0, Fast),
+        (20, 15, 16, 1, 6, 0, Fast),
+        (21, 16, 17, 1, 5, 0, DFast),
+        (21, 18, 18, 1, 5, 0, DFast),
+        (21, 18, 19, 3, 5, 2, Greedy),
+        (21, 18, 19, 3, 5, 4, Lazy),
+        (21, 19, 20, 4, 5, 8, Lazy),
+        (21, 19, 20, 4, 5, 16, Lazy2),
+        (22, 20, 21, 4, 5, 16, Lazy2),
+        (22, 21, 22, 5, 5, 16, Lazy2),
+        (22, 21, 22, 6, 5, 16, Lazy2),
+        (22, 22, 23, 6, 5, 32, Lazy2),
+        (22, 22, 22, 4, 5, 32, BtLazy2),
+        (22, 22, 23, 5, 5, 32, BtLazy2),
+        (22, 23, 23, 6, 5, 32, BtLazy2),
+        (22, 22, 22, 5, 5, 48, BtOpt),
+        (23, 23, 22, 5, 4, 64, BtOpt),
+        (23, 23, 22, 6, 3, 64, BtUltra),
+        (23, 24, 22, 7, 3, 256, BtUltra2),
+        (25, 25, 23, 7, 3, 256, BtUltra2),
+        (26, 26, 24, 7, 3, 512, BtUltra2),
+        (27, 27, 25, 9, 3, 999, BtUltra2),
+    ]
+};
+
+impl CompressionParams {
+    /// Get the parameters for a compression level.  Level 0 selects the
+    /// default level (3), levels above 22 are clamped, and negative levels
+    /// trade ratio for speed by skipping positions.
+    pub fn level(level: i32) -> Self {
+        let level = match level {
+            0 => DEFAULT_LEVEL,
+            _ => level.clamp(MIN_LEVEL, MAX_LEVEL),
+        };
+        let row = LEVELS[level.max(0) as usize];
+        let mut params = Self {
+            level,
+            window_log: row.0,
+            window_mantissa: 0,
+            chain_log: row.1,
+            hash_log: row.2,
+            search_log: row.3,
+            min_match: row.4,
+            target_length: row.5,
+            strategy: row.6,
+            checksum: true,
+            content_size: true,
+            dictionary_id: true,
+            long_distance: false,
+            job_size: 0,
+            // Stronger strategies make more of a longer history.
+            overlap_log: match row.6 {
+                Strategy::BtUltra2 => 9,
+                Strategy::BtUltra => 8,
+                Strategy::BtLazy2 | Strategy::BtOpt => 7,
+                _ => 6,
+            },
+            block_split: row.6 >= Strategy::Greedy,
+            rsyncable: false,
+            target_block_size: 0,
+        };
+        if level < 0 {
+            params.target_length = -level as u32;
+        }
+        params
+    }
+
+    /// Set the window to `1 << log` bytes (10 to 31).
+    pub fn window_log(mut self, log: u8) -> Self {
+        self.window_log = log.clamp(WINDOW_LOG_MIN, WINDOW_LOG_MAX);
+        self.window_mantissa = 0;
+        self
+    }
+
+    /// Set the window size, rounded up to the next size a frame header can
+    /// describe (1 KiB to 2 GiB, with eight steps between powers of two).
+    pub fn window_size(mut self, size: u64) -> Self {
+        let size = size.clamp(1 << WINDOW_LOG_MIN, 1 << WINDOW_LOG_MAX);
+        let log = 63 - size.leading_zeros() as u8;
+        let step = (1u64 << log) / 8;
+        let mantissa = (size - (1 << log)).div_ceil(step);
+        if mantissa == 8 {
+            self.window_log = log + 1;
+            self.window_mantissa = 0;
+        } else {
+            self.window_log = log;
+            self.window_mantissa = mantissa as u8;
+        }
+        self
+    }
+
+    /// Set the log2 size of the chain table (6 to 30).  Only used by the
+    /// hash chain and binary tree strategies.
+    pub fn chain_log(mut self, log: u8) -> Self {
+        self.chain_log = log.clamp(6, 30);
+        self
+    }
+
+    /// Set the log2 size of the hash table (6 to 30).
+    pub fn hash_log(mut self, log: u8) -> Self {
+        self.hash_log = log.clamp(6, 30);
+        self
+    }
+
+    /// Set the log2 number of match searches per position (1 to 30).
+    pub fn search_log(mut self, log: u8) -> Self {
+        self.search_log = log.clamp(1, 30);
+        self
+    }
+
+    /// Set the shortest match searched for (3 to 7).
+    pub fn min_match(mut self, len: u8) -> Self {
+        self.min_match = len.clamp(3, 7);
+        self
+    }
+
+    /// Set the target length (0 to 131072), whose meaning depends on the
+    /// strategy: the acceleration for `Fast`, and the length at which a
+    /// match is considered good enough for the optimal parsing ones.
+    pub fn target_length(mut self, len: u32) -> Self {
+        self.target_length = len.min(1 << 17);
+        self
+    }
+
+    /// Set the match finding strategy.
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Append a checksum of the content to each frame, which decoders use
+    /// to detect corruption (on by default, like the zstd command line).
+    pub fn checksum(mut self, enabled: bool) -> Self {
+        self.checksum = enabled;
+        self
+    }
+
+    /// Write the content size in frame headers when it is known (on by
+    /// default).
+    pub fn content_size(mut self, enabled: bool) -> Self {
+        self.content_size = enabled;
+        self
+    }
+
+    /// Write the dictionary ID in frame headers when compressing with a
+    /// dictionary (on by default).
+    pub fn dictionary_id(mut self, enabled: bool) -> Self {
+        self.dictionary_id = enabled;
+        self
+    }
+
+    /// Also search for matches far back in a large window (off by default),
+    /// for data with repeats that are hundreds of megabytes apart.  Raises
+    /// the window to at least 128 MiB; set a larger one (up to 2 GiB) after
+    /// this.  Decoders need a window limit at least as large.
+    pub fn long_distance(mut self, enabled: bool) -> Self {
+        self.long_distance = enabled;
+        if enabled && self.window_log < LDM_WINDOW_LOG {
+            self.window_log = LDM_WINDOW_LOG;
+            self.window_mantissa = 0;
+        }
+        self
+    }
+
+    /// Set the amount of input in each job when compressing with several
+    /// workers (at least 256 KiB), or 0 to use four times the window size
+    /// (1 MiB to 1 GiB), which is the default.  The output depends on the
+    /// job size, but not on the number of workers.
+    pub fn job_size(mut self, size: usize) -> Self {
+        self.job_size = if size == 0 { 0 } else { size.clamp(1 << 18, 1 << 30) };
+        self
+    }
+
+    /// Set how much of the data before each job its matches may refer to
+    /// when compressing with several workers, from 0 (none) to 9 (the whole
+    /// window).  Each step down halves it.  The default depends on the
+    /// strategy: from 6 (an eighth of the window) to 9.
+    pub fn overlap_log(mut self, log: u8) -> Self {
+        self.overlap_log = log.min(9);
+        self
+    }
+
+    /// Split blocks where the statistics of the data change, such as text
+    /// followed by binary data, so each part gets entropy tables that fit
+    /// it.  On by default from the `Greedy` strategy (level 5) up; the
+    /// faster levels leave it off, since it costs more time than they
+    /// spend on the rest of the block.
+    pub fn block_split(mut self, enabled: bool) -> Self {
+        self.block_split = enabled;
+        self
+    }
+
+    /// End jobs at points picked by a rolling hash of the input, as well
+    /// as at the job size (off by default), like `zstd --rsyncable`.  An
+    /// edit then only changes the output up to the jobs after it, so tools
+    /// like rsync can send just that part.  This costs a little ratio.
+    /// Encoders compress rsyncable frames on at least one worker thread.
+    pub fn rsyncable(mut self, enabled: bool) -> Self {
+        self.rsyncable = enabled;
+        self
+    }
+
+    /// End blocks once their estimated compressed size reaches `size`
+    /// (at least 1340 bytes), rather than after 128 KiB of input, like
+    /// zstd's `targetCBlockSize`.  Small blocks let a decoder start on a
+    /// stream sooner, such as one block per network packet, at some cost in
+    /// ratio.  0, the default, turns this off.
+    pub fn target_block_size(mut self, size: usize) -> Self {
+        self.target_block_size =
+            if size == 0 { 0 } else { size.clamp(TARGET_BLOCK_SIZE_MIN, BLOCK_SIZE_MAX) };
+        self
+    }
+
+    /// Replace the compression parameters with those of a level, keeping the
+    /// frame flags, long distance matching, rsyncable mode and the target
+    /// block size.
+    pub(crate) fn with_level(self, level: i32) -> Self {
+        Self {
+            checksum: self.checksum,
+            content_size: self.content_size,
+            dictionary_id: self.dictionary_id,
+            rsyncable: self.rsyncable,
+            target_block_size: self.target_block_size,
+            ..Self::level(level)
+        }
+        .long_distance(self.long_distance)
+    }
+
+    /// Switch to the parameters of another level in the middle of a frame,
+    /// keeping the window its header gave, the job size and overlap, and
+    /// whether blocks are split.
+    pub(crate) fn at_level(self, level: i32) -> Self {
+        let mut params = Self {
+            job_size: self.job_size,
+            overlap_log: self.overlap_log,
+            block_split: self.block_split,
+            ..self.with_level(level)
+        };
+        params.window_log = self.window_log;
+        params.window_mantissa = self.window_mantissa;
+        params.adjust(self.window() as u64)
+    }
+
+    /// Shrink the tables and window when the amount of data to compress
+    /// is known, so small inputs don't pay for large tables.
+    pub(crate) fn adjust(mut self, src_size: u64) -> Self {
+        let src_log = if src_size < 64 {
+            6
+        } else {
+            64 - (src_size - 1).leading_zeros() as u8
+        };
+        if (1u64 << src_log.min(63)) < self.window() as u64 {
+            self.window_log = src_log.max(WINDOW_LOG_MIN);
+            self.window_mantissa = 0;
+        }
+        self.hash_log = self.hash_log.min(self.window_log + 1);
+        let cycle_log = if self.strategy >= Strategy::BtLazy2 {
+            self.chain_log - 1
+        } else {
+            self.chain_log
+        };
+        if cycle_log > self.window_log {
+            self.chain_log -= cycle_log - self.window_log;
+        }
+        self
+    }
+
+    /// The Window_Descriptor byte of a frame header.
+    pub(crate) fn window_descriptor(&self) -> u8 {
+        (self.window_log - WINDOW_LOG_MIN) << 3 | self.window_mantissa
+    }
+
+    /// The largest back-reference distance.
+    pub(crate) fn window(&self) -> usize {
+        let base = 1usize << self.window_log;
+        base + base / 8 * usize::from(self.window_mantissa)
+    }
+
+    /// The amount of input in each job of a multi-threaded frame.
+    pub(crate) fn job(&self) -> usize {
+        match self.job_size {
+            0 => (self.window() * 4).clamp(1 << 20, 1 << 30),
+            size => size,
+        }
+    }
+
+    /// The amount of data before each job that its matches may refer to.
+    pub(crate) fn overlap(&self) -> usize {
+        match self.overlap_log {
+            0 => 0,
+            log => self.window() >> (9 - log),
+        }
+    }
+
+    /// The largest amount of data in one block.
+    pub(crate) fn block_size(&self) -> usize {
+        self.window().min(BLOCK_SIZE_MAX)
+    }
+}
+
+impl Default for CompressionParams {
+    fn default() -> Self {
+        Self::level(DEFAULT_LEVEL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompressionParams as Params, Strategy};
+
+    #[test]
+    fn level_table() {
+        assert_eq!(Params::level(0), Params::level(3));
+        assert_eq!(Params::level(3).strategy, Strategy::DFast);
+        assert_eq!(Params::level(19).strategy, Strategy::BtUltra2);
+        assert_eq!(Params::level(100), Params::level(22));
+        assert_eq!(Params::level(22).window_log, 27);
+        assert!(!Params::level(4).block_split);
+        assert!(Params::level(5).block_split);
+    }
+
+    #[test]
+    fn switching_level_keeps_settings() {
+        let params = Params::level(3).overlap_log(2).block_split(true).window_log(20);
+        let switched = params.at_level(19);
+        assert_eq!(switched.strategy, Strategy::BtUltra2);
+        assert_eq!(switched.window_log, 20);
+        assert_eq!(switched.overlap_log, 2);
+        assert!(switched.block_split);
+        assert!(!Params::level(19).block_split(false).at_level(5).block_split);
+    }
+
+    #[test]
+    fn negative_levels() {
+        let params = Params::level(-5);
+        assert_eq!(params.strategy, Strategy::Fast);
+        assert_eq!(params.target_length, 5);
+        assert_eq!(Params::level(i32::MIN).target_length, 1 << 17);
+    }
+
+    #[test]
+    fn long_distance_window() {
+        let params = Params::level(1).long_distance(true);
+        assert_eq!(params.window_log, 27);
+        assert_eq!(params.with_level(19).window_log, 27);
+        assert_eq!(params.window_log(31).long_distance(true).window_log, 31);
+        assert!(!Params::level(1).long_distance);
+    }
+
+    #[test]
+    fn adjust_small_input() {
+        let params = Params::level(22).adjust(1000);
+        assert_eq!(params.window_log, 10);
+        assert_eq!(params.hash_log, 11);
+        assert_eq!(params.chain_log, 11);
+    }
+
+    #[test]
+    fn clamped_values() {
+        let params = Params::default()
+            .window_log(40)
+            .hash_log(2)
+            .chain_log(31)
+            .search_log(0)
+            .min_match(9)
+            .target_length(u32::MAX);
+        assert_eq!(params.window_log, 31);
+        assert_eq!(params.hash_log, 6);
+        assert_eq!(params.chain_log, 30);
+        assert_eq!(params.search_log, 1);
+        assert_eq!(params.min_match, 7);
+        assert_eq!(params.target_length, 1 << 17);
+    }
+
+    #[test]
+    fn exact_window() {
+        let params = Params::default().window_size(3 << 20);
+        assert_eq!(params.window(), 3 << 20);
+        assert_eq!(params.window_descriptor(), (21 - 10) << 3 | 4);
+        // 8 MB rounds up to 8 MiB.
+        let params = Params::default().window_size(8_000_000);
+        assert_eq!(params.window(), 8 << 20);
+        assert_eq!(params.window_descriptor(), (23 - 10) << 3);
+        // Just above 7.875 MiB needs the next power of two.
+        let params = Params::default().window_size((63 << 17) + 1);
+        assert_eq!(params.window(), 8 << 20);
+        assert_eq!(Params::default().window_size(100_000).window(), 13 << 13);
+    }
+}
@@ -1,4 +1,4 @@
-//! 
+//! Little endian parsing.
 
 // TODO: Separate out into a library that can be additionally used by png_pong
 #![allow(unused)]
@@ -0,0 +1,284 @@
+//! Row hash match finder: each hash picks a row of recent positions, and a
+//! tag byte kept for each entry rejects most candidates without reading
+//! the data.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/compress/zstd_lazy.c
+
+use super::matcher::{self, MatchFinder, HASH_READ_SIZE};
+use super::params::CompressionParams;
+use super::sequence::Sequence;
+
+/// Log2 of the entries in a row.
+const ROW_LOG: u8 = 3;
+/// Entries in a row, one tag byte each so a row's tags fit in a `u64`.
+const ROW_SIZE: usize = 1 << ROW_LOG;
+/// Bits of the hash kept as the tag.
+const TAG_BITS: u8 = 8;
+/// How quickly to skip ahead through data without matches.
+const SEARCH_STRENGTH: u32 = 8;
+
+/// Recent positions sharing a hash, with a tag byte for each.  Adding an
+/// entry shifts the tags, but only writes one position.
+#[derive(Clone, Copy)]
+struct Row {
+    /// Tags, newest in the lowest byte.
+    tags: u64,
+    /// Positions in a ring, the one for byte `i` of the tags in slot
+    /// `head + i`.
+    positions: [u32; ROW_SIZE],
+    head: usize,
+}
+
+impl Row {
+    const EMPTY: Self = Self { tags: 0, positions: [0; ROW_SIZE], head: 0 };
+
+    /// The top bit of each byte whose entry has the tag `tag`.  An entry
+    /// just after a matching one can be set too, which the check against
+    /// the data rejects.
+    #[inline(always)]
+    fn matching(&self, tag: u8) -> u64 {
+        const LOW: u64 = u64::MAX / 255;
+        let diff = self.tags ^ (LOW * u64::from(tag));
+        diff.wrapping_sub(LOW) & !diff & (LOW << 7)
+    }
+
+    /// Add a position, dropping the oldest entry.
+    #[inline(always)]
+    fn insert(&mut self, tag: u8, pos: usize) {
+        self.tags = self.tags << 8 | u64::from(tag);
+        self.head = self.head.wrapping_sub(1) & (ROW_SIZE - 1);
+        self.positions[self.head] = pos as u32;
+    }
+}
+
+/// Finds matches by checking the most recent position in a row whose tag
+/// matches.
+pub(crate) struct RowMatcher {
+    rows: Vec<Row>,
+    row_log: u8,
+    min_match: u8,
+    window: usize,
+    rep: [u32; 2],
+}
+
+impl RowMatcher {
+    /// Create a row hash match finder.
+    pub(crate) fn new(params: &CompressionParams) -> Self {
+        let row_log = params.hash_log.saturating_sub(ROW_LOG).max(1);
+        Self {
+            rows: vec![Row::EMPTY; 1 << row_log],
+            row_log,
+            min_match: params.min_match.clamp(4, 7),
+            window: params.window(),
+            rep: [1, 4],
+        }
+    }
+
+    /// Row and tag for the position `pos`.
+    #[inline(always)]
+    fn locate(&self, data: &[u8], pos: usize) -> (usize, u8) {
+        let hash = matcher::hash(data, pos, self.min_match, self.row_log + TAG_BITS);
+        (hash >> TAG_BITS, hash as u8)
+    }
+
+    /// Check the newest entry with the same tag as `ip` for a match, and
+    /// add `ip` to the row: (length, offset), or `None`.
+    #[inline(always)]
+    fn search(&mut self, data: &[u8], ip: usize, end: usize) -> Option<(usize, usize)> {
+        let (index, tag) = self.locate(data, ip);
+        let row = &mut self.rows[index];
+        let mask = row.matching(tag);
+        let slot = (mask.trailing_zeros() as usize / 8 + row.head) & (ROW_SIZE - 1);
+        let candidate = row.positions[slot] as usize;
+        row.insert(tag, ip);
+
+        let diff = matcher::read_u64(data, candidate) ^ matcher::read_u64(data, ip);
+        if mask == 0
+            || candidate >= ip
+            || ip - candidate > self.window
+            || diff << (64 - 8 * u32::from(self.min_match)) != 0
+        {
+            return None;
+        }
+        Some((matcher::match_len(data, candidate, ip, end), ip - candidate))
+    }
+
+    /// Add a position without searching from it.
+    #[inline(always)]
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        let (index, tag) = self.locate(data, pos);
+        self.rows[index].insert(tag, pos);
+    }
+}
+
+impl MatchFinder for RowMatcher {
+    fn find(&mut self, data: &[u8], start: usize, end: usize, sequences: &mut Vec<Sequence>) {
+        let limit = end.saturating_sub(HASH_READ_SIZE);
+        let mut anchor = start;
+        let mut ip = start.max(1);
+
+        while ip < limit {
+            // Try the most recent offset one position ahead first.
+            let rep = self.rep[0] as usize;
+            let (mut pos, mut len, offset) = if rep <= ip + 1
+                && rep <= self.window
+                && matcher::read_u32(data, ip + 1 - rep) == matcher::read_u32(data, ip + 1)
+            {
+                self.insert(data, ip);
+                let len = 4 + matcher::match_len(data, ip + 5 - rep, ip + 5, end);
+                (ip + 1, len, rep)
+            } else {
+                match self.search(data, ip, end) {
+                    Some((len, offset)) => (ip, len, offset),
+                    None => {
+                        ip += ((ip - anchor) >> SEARCH_STRENGTH) + 1;
+                        continue;
+                    }
+                }
+            };
+
+            // Extend the match backwards over the literals.
+            while pos > anchor && pos > offset && data[pos - 1] == data[pos - 1 - offset] {
+                pos -= 1;
+                len += 1;
+            }
+
+            sequences.push(Sequence {
+                lit_len: (pos - anchor) as u32,
+                match_len: len as u32,
+                offset: offset as u32,
+            });
+            if offset as u32 != self.rep[0] {
+                self.rep = [offset as u32, self.rep[0]];
+            }
+            ip = pos + len;
+            anchor = ip;
+
+            // Fill in some of the skipped positions.
+            for fill in [pos + 2, ip - 2].iter().cloned() {
+                if fill < limit && fill > pos {
+                    self.insert(data, fill);
+                }
+            }
+
+            // Matches with the second most recent offset need no literals.
+            while ip < limit {
+                let rep = self.rep[1] as usize;
+                if rep > ip
+                    || rep > self.window
+                    || matcher::read_u32(data, ip - rep) != matcher::read_u32(data, ip)
+                {
+                    break;
+                }
+                let len = 4 + matcher::match_len(data, ip + 4 - rep, ip + 4, end);
+                sequences.push(Sequence {
+                    lit_len: 0,
+                    match_len: len as u32,
+                    offset: rep as u32,
+                });
+                self.rep.swap(0, 1);
+                self.insert(data, ip);
+                ip += len;
+                anchor = ip;
+            }
+        }
+    }
+
+    fn slide(&mut self, amount: usize) {
+        for row in &mut self.rows {
+            matcher::slide(&mut row.positions, amount);
+        }
+    }
+
+    fn fill(&mut self, data: &[u8], start: usize, end: usize) {
+        for pos in start.max(1)..end.saturating_sub(HASH_READ_SIZE) {
+            self.insert(data, pos);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{MatchFinder, RowMatcher};
+    use crate::block::BlockEncoder;
+    use crate::fast::FastMatcher;
+    use crate::matcher::tests::execute;
+    use crate::params::CompressionParams;
+    use crate::tests::random;
+    use std::time::{Duration, Instant};
+
+    /// Text made of words picked by a pseudo-random generator.
+    pub(crate) fn words(len: usize) -> Vec<u8> {
+        let words = [
+            "alpha ", "beta ", "gamma ", "delta\n", "epsilon ", "zeta ", "eta, ", "theta ",
+        ];
+        let mut random = random(7);
+        let mut data = Vec::with_capacity(len + 8);
+        while data.len() < len {
+            let state = random.next().unwrap();
+            data.extend_from_slice(words[(state >> 16) as usize % words.len()].as_bytes());
+            data.extend_from_slice(format!("{} ", state % 1000).as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn sequences_rebuild_input() {
+        let data = words(200_000);
+        for level in 1..=2 {
+            let mut matcher = RowMatcher::new(&CompressionParams::level(level));
+            let mut sequences = Vec::new();
+            let middle = data.len() / 2;
+            matcher.find(&data, 0, middle, &mut sequences);
+            assert_eq!(execute(&data, 0, middle, &sequences), &data[..middle]);
+            let mut more = Vec::new();
+            matcher.find(&data, middle, data.len(), &mut more);
+            assert_eq!(execute(&data, middle, data.len(), &more), data);
+        }
+    }
+
+    /// Throughput against the single entry hash finder.  Run with
+    /// `cargo test --release -- --ignored --nocapture throughput`.
+    #[test]
+    #[ignore]
+    fn throughput() {
+        let data = words(32 << 20);
+        let block = 1 << 17;
+        let run = |name: &str, matcher: &mut dyn MatchFinder| {
+            let mut encoder = BlockEncoder::new();
+            let mut elapsed = Duration::default();
+            let mut size = 0;
+            let mut sequences = Vec::new();
+            for start in (0..data.len()).step_by(block) {
+                let end = (start + block).min(data.len());
+                sequences.clear();
+                let started = Instant::now();
+                matcher.find(&data, start, end, &mut sequences);
+                elapsed += started.elapsed();
+                size += encoder
+                    .compress(&data[start..end], &sequences)
+                    .map_or(end - start, |out| out.len());
+            }
+            let seconds = elapsed.as_secs_f64();
+            println!(
+                "{}: {:.0} MB/s, {} -> {} bytes",
+                name,
+                data.len() as f64 / seconds / 1e6,
+                data.len(),
+                size
+            );
+        };
+        for level in 1..=2 {
+            let params = CompressionParams::level(level);
+            run(
+                &format!("level {} hash", level),
+                &mut FastMatcher::new(&params),
+            );
+            run(
+                &format!("level {} row", level),
+                &mut RowMatcher::new(&params),
+            );
+        }
+    }
+}
+
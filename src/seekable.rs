@@ -0,0 +1,464 @@
+//! The seekable format: independent frames, followed by a skippable frame
+//! with a table of their sizes, so a range of the content can be decoded
+//! without the frames before it.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+
+use std::io::{Error as IoError, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use super::encoder::Counted;
+use super::parser::LeDecoder;
+use super::xxhash::Xxh64;
+use super::{CompressionParams, DecError, Decoder, Encoder, FrameHeader, FrameType};
+
+/// Magic number at the very end of a seekable stream.
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+/// Low 4 bits of the seek table's skippable frame magic number.
+const SEEK_TABLE_VARIANT: u8 = 0xE;
+/// Frame count, descriptor and magic number after the table's entries.
+const FOOTER_SIZE: u64 = 9;
+/// Magic number and size of the skippable frame.
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+/// Magic number of the skippable frame holding the seek table.
+const SEEK_TABLE_MAGIC: u32 = 0x184D2A50 | SEEK_TABLE_VARIANT as u32;
+/// Largest frame content other seekable readers take (1 GiB).
+const FRAME_SIZE_MAX: u32 = 1 << 30;
+/// Content size at which frames end unless set otherwise (1 MiB).
+const FRAME_SIZE_DEFAULT: u32 = 1 << 20;
+
+/// A decoder for the seekable format that implements
+/// [`Read`](std::io::Read) and [`Seek`](std::io::Seek) over the content,
+/// decoding only the frames that hold what's read.
+pub struct SeekableDecoder<R: Read + Seek> {
+    reader: R,
+    /// Start of each frame, in the stream and in the content, then the end
+    /// of the last one.
+    frames: Vec<(u64, u64)>,
+    /// The low 32 bits of the XXH64 of each frame's content, if the table
+    /// has them.
+    checksums: Option<Vec<u32>>,
+    /// Position in the content.
+    pos: u64,
+    /// The frame decoded last, and its content.
+    frame: Option<usize>,
+    data: Vec<u8>,
+}
+
+impl<R: Read + Seek> SeekableDecoder<R> {
+    /// Read the seek table at the end of `reader`.
+    pub fn new(mut reader: R) -> Result<Self, IoError> {
+        ///////////////////// Seek_Table_Footer ////////////////////
+
+        let end = reader.seek(SeekFrom::End(0))?;
+        if end < SKIPPABLE_HEADER_SIZE + FOOTER_SIZE {
+            Err(DecError::SeekTable)?
+        }
+        reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        let mut dec = LeDecoder::new(&mut reader);
+        let count = dec.u32()?;
+        let descriptor = dec.u8()?;
+        if dec.u32()? != SEEKABLE_MAGIC || descriptor & 0b0111_1100 != 0 {
+            Err(DecError::SeekTable)?
+        }
+        let checksum = descriptor & 0b1000_0000 != 0;
+        let entry_size = if checksum { 12 } else { 8 };
+
+        ///////////////////// Seek_Table_Entries ////////////////////
+
+        let table_size = u64::from(count) * entry_size + FOOTER_SIZE;
+        if table_size + SKIPPABLE_HEADER_SIZE > end {
+            Err(DecError::SeekTable)?
+        }
+        let table_start = end - table_size - SKIPPABLE_HEADER_SIZE;
+        reader.seek(SeekFrom::Start(table_start))?;
+        let header = FrameHeader::read(&mut reader)?;
+        let skippable = FrameType::Skippable(SEEK_TABLE_VARIANT);
+        if header.frame_type != skippable || header.content_size != Some(table_size) {
+            Err(DecError::SeekTable)?
+        }
+        let mut dec = LeDecoder::new(&mut reader);
+        let mut frames = Vec::with_capacity(count as usize + 1);
+        let mut checksums = Vec::new();
+        let (mut compressed, mut decompressed) = (0, 0);
+        for _ in 0..count {
+            frames.push((compressed, decompressed));
+            compressed += u64::from(dec.u32()?);
+            decompressed += u64::from(dec.u32()?);
+            if checksum {
+                checksums.push(dec.u32()?);
+            }
+        }
+        frames.push((compressed, decompressed));
+        if compressed > table_start {
+            Err(DecError::SeekTable)?
+        }
+
+        Ok(Self {
+            reader,
+            frames,
+            checksums: if checksum { Some(checksums) } else { None },
+            pos: 0,
+            frame: None,
+            data: Vec::new(),
+        })
+    }
+
+    /// Size of the content, from the seek table.
+    pub fn content_size(&self) -> u64 {
+        self.frames.last().unwrap().1
+    }
+
+    /// Number of frames holding the content.
+    pub fn frame_count(&self) -> usize {
+        self.frames.len() - 1
+    }
+
+    /// Decode frame `index`, unless it's the one decoded last.
+    fn load(&mut self, index: usize) -> Result<(), IoError> {
+        if self.frame == Some(index) {
+            return Ok(());
+        }
+        self.frame = None;
+        let (start, content_start) = self.frames[index];
+        let (end, content_end) = self.frames[index + 1];
+        self.reader.seek(SeekFrom::Start(start))?;
+        self.data.clear();
+        let frame = (&mut self.reader).take(end - start);
+        // One byte more than the table says is enough to tell it's wrong.
+        let mut content = Decoder::new(frame).take(content_end - content_start + 1);
+        content.read_to_end(&mut self.data)?;
+        if self.data.len() as u64 != content_end - content_start {
+            Err(DecError::SeekTable)?
+        }
+        if let Some(checksums) = &self.checksums {
+            let mut hasher = Xxh64::new();
+            hasher.update(&self.data);
+            if hasher.digest() as u32 != checksums[index] {
+                Err(DecError::Checksum)?
+            }
+        }
+        self.frame = Some(index);
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableDecoder<R> {
+    /// Decodes the frame holding the current position if it isn't the one
+    /// decoded last.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if buf.is_empty() || self.pos >= self.content_size() {
+            return Ok(0);
+        }
+        // The last frame starting at or before the position holds it
+        // (frames before it can be empty).
+        let index = self.frames.partition_point(|&(_, start)| start <= self.pos) - 1;
+        self.load(index)?;
+        let data = &self.data[(self.pos - self.frames[index].1) as usize..];
+        let amt_to_copy = data.len().min(buf.len());
+        buf[..amt_to_copy].copy_from_slice(&data[..amt_to_copy]);
+        self.pos += amt_to_copy as u64;
+        Ok(amt_to_copy)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableDecoder<R> {
+    /// Moves within the content, which is cheap: frames are only decoded
+    /// once they're read.  Positions past the end read nothing.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, IoError> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.content_size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            IoError::new(ErrorKind::InvalidInput, "seek to before the start")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// An encoder for the seekable format that implements
+/// [`Write`](std::io::Write): the content is cut into frames of a set size,
+/// which [`finish`](Self::finish) follows with the table of their sizes
+/// that [`SeekableDecoder`] and zstd's own seekable readers look for.
+pub struct SeekableEncoder<W: Write> {
+    /// The writer between frames.  This and the frame are both `None` once
+    /// a frame fails to finish.
+    writer: Option<Counted<W>>,
+    /// The frame being written, which holds the writer.
+    frame: Option<Encoder<Counted<W>>>,
+    params: CompressionParams,
+    frame_size: u32,
+    /// Content written to the frame so far, and its hash.
+    frame_len: u32,
+    hasher: Xxh64,
+    /// Whether the table has checksums of each frame's content.
+    checksum: bool,
+    /// Where the frame starts in the output.
+    frame_offset: u64,
+    /// Compressed size, content size and checksum of the frames so far.
+    entries: Vec<(u32, u32, u32)>,
+}
+
+impl<W: Write> SeekableEncoder<W> {
+    /// Create a new seekable encoder that writes to a `Write`r.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Some(Counted { inner: writer, count: 0 }),
+            frame: None,
+            params: CompressionParams::default(),
+            frame_size: FRAME_SIZE_DEFAULT,
+            frame_len: 0,
+            hasher: Xxh64::new(),
+            checksum: false,
+            frame_offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Set the compression level of the frames (see [`Encoder::level`]).
+    pub fn level(mut self, level: i32) -> Self {
+        self.params = self.params.with_level(level);
+        self
+    }
+
+    /// Replace all parameters of the frames.
+    pub fn params(mut self, params: CompressionParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// End each frame once it holds `size` bytes of content (clamped to
+    /// between 1 byte and 1 GiB, the most other readers take; 1 MiB by
+    /// default).  Smaller frames make reading from any position cheaper,
+    /// and compress worse.
+    pub fn frame_size(mut self, size: u32) -> Self {
+        self.frame_size = size.clamp(1, FRAME_SIZE_MAX);
+        self
+    }
+
+    /// Put the low 32 bits of the XXH64 of each frame's content in the seek
+    /// table (off by default).  This is apart from the frames' own
+    /// checksums, which [`CompressionParams::checksum`] sets.
+    pub fn checksum(mut self, enabled: bool) -> Self {
+        self.checksum = enabled;
+        self
+    }
+
+    /// End the last frame, write the seek table, then flush and return the
+    /// inner writer.
+    pub fn finish(mut self) -> Result<W, IoError> {
+        self.end_frame()?;
+        let mut writer = self.writer.take().ok_or_else(failed)?.inner;
+        let entry_size = if self.checksum { 12 } else { 8 };
+        let table_size = self.entries.len() * entry_size + FOOTER_SIZE as usize;
+        if table_size > u32::MAX as usize {
+            return Err(IoError::new(ErrorKind::InvalidInput, "too many frames for a seek table"));
+        }
+
+        let mut table = Vec::with_capacity(SKIPPABLE_HEADER_SIZE as usize + table_size);
+        table.extend_from_slice(&SEEK_TABLE_MAGIC.to_le_bytes());
+        table.extend_from_slice(&(table_size as u32).to_le_bytes());
+        for &(compressed, decompressed, checksum) in &self.entries {
+            table.extend_from_slice(&compressed.to_le_bytes());
+            table.extend_from_slice(&decompressed.to_le_bytes());
+            if self.checksum {
+                table.extend_from_slice(&checksum.to_le_bytes());
+            }
+        }
+        table.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        table.push(u8::from(self.checksum) << 7);
+        table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        writer.write_all(&table)?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    /// End the frame, if one is open, and add it to the table.
+    fn end_frame(&mut self) -> Result<(), IoError> {
+        let Some(frame) = self.frame.take() else {
+            return Ok(());
+        };
+        let writer = frame.finish()?;
+        let compressed = writer.count - self.frame_offset;
+        if compressed > u64::from(u32::MAX) {
+            return Err(IoError::new(ErrorKind::InvalidData, "frame too large for a seek table"));
+        }
+        self.entries.push((compressed as u32, self.frame_len, self.hasher.digest() as u32));
+        self.frame_offset = writer.count;
+        self.frame_len = 0;
+        self.hasher = Xxh64::new();
+        self.writer = Some(writer);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for SeekableEncoder<W> {
+    /// Writes up to the end of the frame, ending it there.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let frame = match &mut self.frame {
+            Some(frame) => frame,
+            None => {
+                let writer = self.writer.take().ok_or_else(failed)?;
+                self.frame.insert(Encoder::new(writer).params(self.params))
+            }
+        };
+        let room = (self.frame_size - self.frame_len) as usize;
+        let written = frame.write(&buf[..buf.len().min(room)])?;
+        if self.checksum {
+            self.hasher.update(&buf[..written]);
+        }
+        self.frame_len += written as u32;
+        if self.frame_len == self.frame_size {
+            self.end_frame()?;
+        }
+        Ok(written)
+    }
+
+    /// Flushes the frame (see [`Encoder`]'s `flush`), which stays open.
+    fn flush(&mut self) -> Result<(), IoError> {
+        match (&mut self.frame, &mut self.writer) {
+            (Some(frame), _) => frame.flush(),
+            (None, Some(writer)) => writer.flush(),
+            (None, None) => Err(failed()),
+        }
+    }
+}
+
+/// The error once a frame has failed to finish, taking the writer with it.
+fn failed() -> IoError {
+    IoError::other("an earlier frame failed to finish")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SeekableDecoder, SeekableEncoder};
+    use crate::xxhash::Xxh64;
+    use crate::{compress, decompress, find_frames, FrameType};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    /// A seekable stream of a frame per chunk, with checksums if `checksum`.
+    fn seekable(chunks: &[&[u8]], checksum: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut table = Vec::new();
+        for chunk in chunks {
+            let frame = compress(chunk, 3);
+            out.extend_from_slice(&frame);
+            table.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            table.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            if checksum {
+                let mut hasher = Xxh64::new();
+                hasher.update(chunk);
+                table.extend_from_slice(&(hasher.digest() as u32).to_le_bytes());
+            }
+        }
+        table.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        table.push((checksum as u8) << 7);
+        table.extend_from_slice(&0x8F92EAB1u32.to_le_bytes());
+        out.extend_from_slice(&0x184D2A5Eu32.to_le_bytes());
+        out.extend_from_slice(&(table.len() as u32).to_le_bytes());
+        out.extend_from_slice(&table);
+        out
+    }
+
+    #[test]
+    fn read_ranges() {
+        let text: Vec<u8> =
+            (0..30_000u32).flat_map(|i| format!("{};", i % 900).into_bytes()).collect();
+        let chunks: Vec<&[u8]> = text.chunks(40_000).chain([&[][..]]).collect();
+        for &checksum in &[false, true] {
+            let stream = seekable(&chunks, checksum);
+            let mut decoder = SeekableDecoder::new(Cursor::new(&stream)).unwrap();
+            assert_eq!(decoder.frame_count(), chunks.len());
+            assert_eq!(decoder.content_size(), text.len() as u64);
+
+            // Across a frame boundary.
+            let mut buf = vec![0; 1000];
+            decoder.seek(SeekFrom::Start(79_500)).unwrap();
+            decoder.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, &text[79_500..80_500]);
+            decoder.seek(SeekFrom::Current(-2000)).unwrap();
+            decoder.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, &text[78_500..79_500]);
+
+            decoder.seek(SeekFrom::End(-10)).unwrap();
+            let mut end = Vec::new();
+            decoder.read_to_end(&mut end).unwrap();
+            assert_eq!(end, &text[text.len() - 10..]);
+            assert!(decoder.seek(SeekFrom::Current(-(text.len() as i64) - 1)).is_err());
+
+            decoder.rewind().unwrap();
+            let mut all = Vec::new();
+            decoder.read_to_end(&mut all).unwrap();
+            assert!(all == text);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let stream = seekable(&[b"abc", b"def"], true);
+        assert!(SeekableDecoder::new(Cursor::new(&stream[..stream.len() - 1])).is_err());
+        let mut reserved = stream.clone();
+        let descriptor = reserved.len() - 5;
+        reserved[descriptor] |= 0b100;
+        assert!(SeekableDecoder::new(Cursor::new(&reserved)).is_err());
+
+        // A checksum that doesn't match.
+        let mut corrupted = stream.clone();
+        let entry = corrupted.len() - 9 - 4;
+        corrupted[entry] ^= 1;
+        let mut decoder = SeekableDecoder::new(Cursor::new(&corrupted)).unwrap();
+        let mut buf = [0; 3];
+        decoder.read_exact(&mut buf).unwrap();
+        assert!(decoder.read_exact(&mut buf).is_err());
+
+        // A frame that holds far more than its entry says.
+        let mut understated = seekable(&[&[0; 1 << 20]], false);
+        let entry = understated.len() - 9 - 4;
+        understated[entry..entry + 4].copy_from_slice(&3u32.to_le_bytes());
+        let mut decoder = SeekableDecoder::new(Cursor::new(&understated)).unwrap();
+        assert!(decoder.read_exact(&mut buf).is_err());
+        assert!(decoder.data.len() <= 4);
+    }
+
+    #[test]
+    fn encode() {
+        let text: Vec<u8> =
+            (0..30_000u32).flat_map(|i| format!("{},", i % 800).into_bytes()).collect();
+        for &checksum in &[false, true] {
+            let mut encoder =
+                SeekableEncoder::new(Vec::new()).frame_size(10_000).checksum(checksum);
+            for chunk in text.chunks(7_000) {
+                encoder.write_all(chunk).unwrap();
+            }
+            let stream = encoder.finish().unwrap();
+            assert_eq!(decompress(&stream).unwrap(), text);
+
+            // Frames of the set size, and the table.
+            let frames = find_frames(&stream).unwrap();
+            let count = text.len().div_ceil(10_000);
+            assert_eq!(frames.len(), count + 1);
+            assert_eq!(frames[0].header.content_size, Some(10_000));
+            assert_eq!(frames[count].header.frame_type, FrameType::Skippable(0xE));
+            let table_size = count * if checksum { 12 } else { 8 } + 9;
+            assert_eq!(frames[count].header.content_size, Some(table_size as u64));
+
+            let mut decoder = SeekableDecoder::new(Cursor::new(&stream)).unwrap();
+            assert_eq!(decoder.frame_count(), count);
+            let mut buf = vec![0; 500];
+            decoder.seek(SeekFrom::Start(49_800)).unwrap();
+            decoder.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, &text[49_800..50_300]);
+        }
+
+        // Nothing written: just the table.
+        let stream = SeekableEncoder::new(Vec::new()).finish().unwrap();
+        assert_eq!(stream.len(), 17);
+        let mut decoder = SeekableDecoder::new(Cursor::new(&stream)).unwrap();
+        assert_eq!(decoder.frame_count(), 0);
+        assert_eq!(decoder.read(&mut [0; 4]).unwrap(), 0);
+    }
+}
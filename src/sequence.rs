@@ -0,0 +1,158 @@
+//! Sequences (literal runs followed by matches) and their codes.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#sequences-section
+
+/// Baseline and number of extra bits for each literal length code.
+pub(crate) const LL_CODES: [(u32, u8); 36] = [
+    (0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 0), (7, 0),
+    (8, 0), (9, 0), (10, 0), (11, 0), (12, 0), (13, 0), (14, 0), (15, 0),
+    (16, 1), (18, 1), (20, 1), (22, 1), (24, 2), (28, 2), (32, 3), (40, 3),
+    (48, 4), (64, 6), (128, 7), (256, 8), (512, 9), (1024, 10), (2048, 11),
+    (4096, 12), (8192, 13), (16384, 14), (32768, 15), (65536, 16),
+];
+
+/// Baseline and number of extra bits for each match length code.
+pub(crate) const ML_CODES: [(u32, u8); 53] = [
+    (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0),
+    (11, 0), (12, 0), (13, 0), (14, 0), (15, 0), (16, 0), (17, 0), (18, 0),
+    (19, 0), (20, 0), (21, 0), (22, 0), (23, 0), (24, 0), (25, 0), (26, 0),
+    (27, 0), (28, 0), (29, 0), (30, 0), (31, 0), (32, 0), (33, 0), (34, 0),
+    (35, 1), (37, 1), (39, 1), (41, 1), (43, 2), (47, 2), (51, 3), (59, 3),
+    (67, 4), (83, 4), (99, 5), (131, 7), (259, 8), (515, 9), (1027, 10),
+    (2051, 11), (4099, 12), (8195, 13), (16387, 14), (32771, 15), (65539, 16),
+];
+
+/// A run of literals followed by a match.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Sequence {
+    /// Number of literals copied before the match.
+    pub(crate) lit_len: u32,
+    /// Number of bytes copied by the match.
+    pub(crate) match_len: u32,
+    /// Distance back to the start of the match.
+    pub(crate) offset: u32,
+}
+
+/// Find the code for a value given a code table: (code, extra bits, extra
+/// bits value).
+#[inline(always)]
+fn code(value: u32, codes: &[(u32, u8)]) -> (u8, u8, u32) {
+    let code = codes.partition_point(|&(base, _)| base <= value) - 1;
+    let (base, bits) = codes[code];
+    (code as u8, bits, value - base)
+}
+
+/// Literal length code.
+#[inline(always)]
+pub(crate) fn ll_code(lit_len: u32) -> (u8, u8, u32) {
+    code(lit_len, &LL_CODES)
+}
+
+/// Match length code.
+#[inline(always)]
+pub(crate) fn ml_code(match_len: u32) -> (u8, u8, u32) {
+    code(match_len, &ML_CODES)
+}
+
+/// Offset code for an offset value (repeat code or offset plus three).
+#[inline(always)]
+pub(crate) fn of_code(offset_value: u32) -> (u8, u8, u32) {
+    let code = 31 - offset_value.leading_zeros();
+    (code as u8, code as u8, offset_value - (1 << code))
+}
+
+/// The three most recent offsets, used for repeat offset codes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct RepeatOffsets(pub(crate) [u32; 3]);
+
+impl Default for RepeatOffsets {
+    fn default() -> Self {
+        Self([1, 4, 8])
+    }
+}
+
+impl RepeatOffsets {
+    /// Get the offset value to encode for a match and update the history.
+    pub(crate) fn encode(&mut self, lit_len: u32, offset: u32) -> u32 {
+        let [rep0, rep1, rep2] = self.0;
+        let (value, reps) = if lit_len != 0 {
+            if offset == rep0 {
+                (1, [rep0, rep1, rep2])
+            } else if offset == rep1 {
+                (2, [rep1, rep0, rep2])
+            } else if offset == rep2 {
+                (3, [rep2, rep0, rep1])
+            } else {
+                (offset + 3, [offset, rep0, rep1])
+            }
+        } else if offset == rep1 {
+            (1, [rep1, rep0, rep2])
+        } else if offset == rep2 {
+            (2, [rep2, rep0, rep1])
+        } else if offset + 1 == rep0 {
+            (3, [offset, rep0, rep1])
+        } else {
+            (offset + 3, [offset, rep0, rep1])
+        };
+        self.0 = reps;
+        value
+    }
+
+    /// Get the offset for a decoded offset value and update the history.
+    /// Returns 0 for a repeat of an offset of 0, which is invalid.
+    pub(crate) fn decode(&mut self, lit_len: u32, value: u32) -> u32 {
+        let [rep0, rep1, rep2] = self.0;
+        if value > 3 {
+            self.0 = [value - 3, rep0, rep1];
+            return value - 3;
+        }
+        // Without literals, the repeat codes shift by one.
+        let reps = match value + (lit_len == 0) as u32 {
+            1 => [rep0, rep1, rep2],
+            2 => [rep1, rep0, rep2],
+            3 => [rep2, rep0, rep1],
+            _ => [rep0.wrapping_sub(1), rep0, rep1],
+        };
+        self.0 = reps;
+        reps[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes() {
+        assert_eq!(ll_code(15), (15, 0, 0));
+        assert_eq!(ll_code(17), (16, 1, 1));
+        assert_eq!(ll_code(100_000), (35, 16, 100_000 - 65536));
+        assert_eq!(ml_code(3), (0, 0, 0));
+        assert_eq!(ml_code(131), (43, 7, 0));
+        assert_eq!(of_code(1), (0, 0, 0));
+        assert_eq!(of_code(1027), (10, 10, 3));
+    }
+
+    #[test]
+    fn repeat_offsets() {
+        let mut reps = RepeatOffsets::default();
+        assert_eq!(reps.encode(5, 100), 103);
+        assert_eq!(reps.encode(5, 100), 1);
+        assert_eq!(reps.encode(0, 1), 1);
+        assert_eq!(reps.0, [1, 100, 4]);
+        assert_eq!(reps.encode(0, 99), 102);
+        assert_eq!(reps.encode(0, 98), 3);
+        assert_eq!(reps.0, [98, 99, 1]);
+    }
+
+    #[test]
+    fn decode_what_was_encoded() {
+        let matches = [(5, 100), (5, 100), (0, 1), (0, 99), (0, 98), (3, 1), (0, 50), (0, 98)];
+        let (mut encoder, mut decoder) = (RepeatOffsets::default(), RepeatOffsets::default());
+        for &(lit_len, offset) in &matches {
+            let value = encoder.encode(lit_len, offset);
+            assert_eq!(decoder.decode(lit_len, value), offset);
+        }
+        assert_eq!(encoder, decoder);
+    }
+}
@@ -0,0 +1,202 @@
+//! Block splitting: cutting a block where the statistics of its data change,
+//! so each part gets entropy tables that fit it, or where it reaches a target
+//! compressed size.
+
+// Reference: https://github.com/facebook/zstd/blob/dev/lib/compress/zstd_compress.c (ZSTD_deriveBlockSplits)
+
+use super::block::BlockEncoder;
+use super::sequence::Sequence;
+
+/// Fewest sequences in a part, below which the tables cost more than a
+/// split can save.
+const MIN_SEQUENCES: usize = 300;
+/// Most parts a block is cut into.
+const MAX_PARTS: usize = 32;
+/// Size of a block header, which each part pays for.
+const BLOCK_HEADER_SIZE: usize = 3;
+
+/// A block and its sequences, with where each sequence starts.
+struct Parts<'a> {
+    blocks: &'a BlockEncoder,
+    block: &'a [u8],
+    sequences: &'a [Sequence],
+    /// Start of each sequence in the block, then the end of the block.
+    bounds: Vec<usize>,
+}
+
+impl Parts<'_> {
+    /// Estimated size of the sequences from `lo` to `hi` as a block, from
+    /// the state the whole block starts with.
+    fn cost(&self, lo: usize, hi: usize) -> usize {
+        let block = &self.block[self.bounds[lo]..self.bounds[hi]];
+        self.blocks.estimate(block, &self.sequences[lo..hi]) + BLOCK_HEADER_SIZE
+    }
+
+    /// Split the sequences from `lo` to `hi`, which `cost` as one block, in
+    /// half while that's smaller, adding the sequence indices to cut at to
+    /// `cuts`.
+    fn split(&self, lo: usize, hi: usize, cost: usize, cuts: &mut Vec<usize>) {
+        if hi - lo < 2 * MIN_SEQUENCES || cuts.len() + 1 >= MAX_PARTS {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        let (left, right) = (self.cost(lo, mid), self.cost(mid, hi));
+        if left + right < cost {
+            self.split(lo, mid, left, cuts);
+            cuts.push(mid);
+            self.split(mid, hi, right, cuts);
+        }
+    }
+}
+
+/// Find where to split a block compressed by `blocks` with `sequences`.
+/// Returns the end of each part: its length in the block, and in
+/// sequences.  The last part ends with the block's trailing literals.
+pub(crate) fn split(
+    blocks: &BlockEncoder,
+    block: &[u8],
+    sequences: &[Sequence],
+) -> Vec<(usize, usize)> {
+    let mut bounds = Vec::with_capacity(sequences.len() + 1);
+    let mut pos = 0;
+    for seq in sequences {
+        bounds.push(pos);
+        pos += (seq.lit_len + seq.match_len) as usize;
+    }
+    bounds.push(block.len());
+
+    let parts = Parts { blocks, block, sequences, bounds };
+    let mut cuts = Vec::new();
+    if sequences.len() >= 2 * MIN_SEQUENCES {
+        parts.split(0, sequences.len(), parts.cost(0, sequences.len()), &mut cuts);
+    }
+    cuts.push(sequences.len());
+    cuts.into_iter().map(|cut| (parts.bounds[cut], cut)).collect()
+}
+
+/// Find how much of `block`, with the sequences in it, `blocks` can
+/// compress into `target` bytes, header included.  Returns the length of
+/// that part, and how many sequences it takes.  When not even the first
+/// sequence fits, the part is some of the literals before it, which that
+/// sequence no longer copies.
+pub(crate) fn fit(
+    blocks: &BlockEncoder,
+    block: &[u8],
+    sequences: &mut [Sequence],
+    target: usize,
+) -> (usize, usize) {
+    let target = target.saturating_sub(BLOCK_HEADER_SIZE);
+    // The estimate leaves out some of the headers, so check it against the
+    // real size and aim lower by as much as it was off, or more each time
+    // that doesn't make the part any shorter.
+    let (mut budget, mut previous, mut extra) = (target, block.len() + 1, 0);
+    loop {
+        let (len, count) = fit_estimate(blocks, block, sequences, budget);
+        let size = match blocks.clone().compress(&block[..len], &sequences[..count]) {
+            Some(compressed) => compressed.len(),
+            // Raw blocks are cut to the target as they're written.
+            None => 0,
+        };
+        if size <= target || budget == 0 {
+            if count == 0 {
+                if let Some(seq) = sequences.first_mut() {
+                    seq.lit_len -= len as u32;
+                }
+            }
+            return (len, count);
+        }
+        extra = if len < previous { 0 } else { extra * 2 + 1 };
+        budget = budget.saturating_sub(size - target + extra);
+        previous = len;
+    }
+}
+
+/// Find the longest part of `block` estimated to fit in `target` bytes, in
+/// bytes and in sequences.
+fn fit_estimate(
+    blocks: &BlockEncoder,
+    block: &[u8],
+    sequences: &[Sequence],
+    target: usize,
+) -> (usize, usize) {
+    if blocks.estimate(block, sequences) <= target {
+        return (block.len(), sequences.len());
+    }
+
+    // The end of each sequence's match.
+    let mut ends = Vec::with_capacity(sequences.len());
+    let mut pos = 0;
+    for seq in sequences {
+        pos += (seq.lit_len + seq.match_len) as usize;
+        ends.push(pos);
+    }
+    let fits = |count: usize| {
+        blocks.estimate(&block[..ends[count - 1]], &sequences[..count]) <= target
+    };
+    // Most sequences that fit, between `lo` (which do) and `hi` (which
+    // don't).
+    let (mut lo, mut hi) = (0, sequences.len() + 1);
+    while lo + 1 < hi {
+        let mid = (lo + hi) / 2;
+        if fits(mid) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    if lo > 0 {
+        return (ends[lo - 1], lo);
+    }
+
+    // Most literals that fit, at least one.
+    let mut hi = match sequences.first() {
+        Some(seq) if seq.lit_len == 0 => return (ends[0], 1),
+        Some(seq) => seq.lit_len as usize + 1,
+        None => block.len(),
+    };
+    let mut lo = 1;
+    while lo + 1 < hi {
+        let mid = (lo + hi) / 2;
+        if blocks.estimate(&block[..mid], &[]) <= target {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    (lo, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::BlockEncoder;
+    use crate::matcher;
+    use crate::params::CompressionParams;
+    use crate::tests::random;
+
+    #[test]
+    fn splits_where_data_changes() {
+        // Words, then hex numbers.
+        let mut block = Vec::new();
+        let mut random = random(7);
+        let words = ["alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta"];
+        while block.len() < 60_000 {
+            let state = random.next().unwrap();
+            block.extend_from_slice(words[(state >> 16) as usize % 8].as_bytes());
+            block.push(b' ');
+        }
+        let middle = block.len();
+        while block.len() < 120_000 {
+            let state = random.next().unwrap();
+            block.extend_from_slice(format!("{:X},", state >> 20).as_bytes());
+        }
+
+        let params = CompressionParams::level(5);
+        let mut sequences = Vec::new();
+        matcher::new(&params).find(&block, 0, block.len(), &mut sequences);
+        let parts = super::split(&BlockEncoder::new(), &block, &sequences);
+        assert!(parts.len() >= 2);
+        assert_eq!(parts.last(), Some(&(block.len(), sequences.len())));
+        // A cut lands near the change.
+        assert!(parts.iter().any(|&(end, _)| end.abs_diff(middle) < 16_000));
+    }
+}
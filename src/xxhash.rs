@@ -0,0 +1,161 @@
+//! XXH64, the hash used for content checksums.
+
+// Reference: https://github.com/Cyan4973/xxHash/blob/dev/doc/xxhash_spec.md
+
+use std::convert::TryInto;
+
+const PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+/// Streaming XXH64 hasher (seed 0).
+#[derive(Clone)]
+pub(crate) struct Xxh64 {
+    acc: [u64; 4],
+    buf: [u8; 32],
+    buf_len: usize,
+    total_len: u64,
+}
+
+impl Default for Xxh64 {
+    fn default() -> Self {
+        Self {
+            acc: [
+                PRIME_1.wrapping_add(PRIME_2),
+                PRIME_2,
+                0,
+                0u64.wrapping_sub(PRIME_1),
+            ],
+            buf: [0; 32],
+            buf_len: 0,
+            total_len: 0,
+        }
+    }
+}
+
+#[inline(always)]
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+#[inline(always)]
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME_1)
+}
+
+#[inline(always)]
+fn merge_round(acc: u64, value: u64) -> u64 {
+    (acc ^ round(0, value)).wrapping_mul(PRIME_1).wrapping_add(PRIME_4)
+}
+
+impl Xxh64 {
+    /// Create a new hasher.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consume one 32 byte stripe.
+    #[inline(always)]
+    fn stripe(acc: &mut [u64; 4], stripe: &[u8]) {
+        for (i, lane) in acc.iter_mut().enumerate() {
+            *lane = round(*lane, read_u64(&stripe[i * 8..]));
+        }
+    }
+
+    /// Hash more data.
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        if self.buf_len != 0 {
+            let amt = (32 - self.buf_len).min(data.len());
+            self.buf[self.buf_len..][..amt].copy_from_slice(&data[..amt]);
+            self.buf_len += amt;
+            data = &data[amt..];
+            if self.buf_len < 32 {
+                return;
+            }
+            let buf = self.buf;
+            Self::stripe(&mut self.acc, &buf);
+            self.buf_len = 0;
+        }
+        let mut stripes = data.chunks_exact(32);
+        for stripe in &mut stripes {
+            Self::stripe(&mut self.acc, stripe);
+        }
+        let rest = stripes.remainder();
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.buf_len = rest.len();
+    }
+
+    /// Get the hash of everything so far.
+    pub(crate) fn digest(&self) -> u64 {
+        let mut hash = if self.total_len >= 32 {
+            let [a, b, c, d] = self.acc;
+            let mut hash = a
+                .rotate_left(1)
+                .wrapping_add(b.rotate_left(7))
+                .wrapping_add(c.rotate_left(12))
+                .wrapping_add(d.rotate_left(18));
+            for &lane in &self.acc {
+                hash = merge_round(hash, lane);
+            }
+            hash
+        } else {
+            PRIME_5
+        };
+        hash = hash.wrapping_add(self.total_len);
+
+        let mut rest = &self.buf[..self.buf_len];
+        while rest.len() >= 8 {
+            hash ^= round(0, read_u64(rest));
+            hash = hash.rotate_left(27).wrapping_mul(PRIME_1).wrapping_add(PRIME_4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            let lane = u32::from_le_bytes(rest[..4].try_into().unwrap());
+            hash ^= u64::from(lane).wrapping_mul(PRIME_1);
+            hash = hash.rotate_left(23).wrapping_mul(PRIME_2).wrapping_add(PRIME_3);
+            rest = &rest[4..];
+        }
+        for &byte in rest {
+            hash ^= u64::from(byte).wrapping_mul(PRIME_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME_3);
+        hash ^ (hash >> 32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Xxh64;
+
+    fn hash(data: &[u8]) -> u64 {
+        let mut hasher = Xxh64::new();
+        hasher.update(data);
+        hasher.digest()
+    }
+
+    #[test]
+    fn known_values() {
+        assert_eq!(hash(b""), 0xEF46_DB37_51D8_E999);
+        assert_eq!(hash(b"abc"), 0x44BC_2CF5_AD77_0999);
+    }
+
+    #[test]
+    fn streaming_matches_one_shot() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        let mut hasher = Xxh64::new();
+        for chunk in data.chunks(17) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.digest(), hash(&data));
+    }
+}